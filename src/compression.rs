@@ -0,0 +1,296 @@
+//! Backup compression
+//!
+//! Backups are compressed before the upload and stored with a ".gz" suffix on their
+//! extension, so listing, restore and inspect recognize compressed objects by their
+//! key alone.
+//!
+//! The input is read in chunks of a fixed size, each compressed into a gzip member
+//! of its own. Members are independent, so several chunks are compressed at once on
+//! separate threads, and only those chunks of the input and their members are held
+//! in memory; the members are handed on as they are done. The concatenated members
+//! are a valid gzip file, decompressed by `gunzip` as one.
+//!
+//! gzip is used rather than zstd, whose bindings would build its C library from
+//! source; see `CompressionCodec`.
+
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Suffix of the keys of gzip compressed backups
+pub const GZIP_SUFFIX: &str = ".gz";
+
+/// How a backup is compressed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    /// gzip level (0-9)
+    pub level: u32,
+    /// Bytes of input per gzip member
+    pub buffer_size: usize,
+    /// Chunks compressed at the same time
+    pub threads: usize,
+}
+
+/// The input of a compression
+#[derive(Debug)]
+pub struct Compressed {
+    /// Bytes of input compressed
+    pub size: u64,
+    /// Start of the input, for the checks of its header
+    pub head: Vec<u8>,
+    /// Most bytes held at once by the input chunks and their members
+    pub peak_memory: usize,
+}
+
+/// Compress everything read from `reader`, keeping its first `head_size` bytes
+///
+/// The gzip members are passed to `output` in order, once compressed. An error
+/// returned by `output` stops the compression.
+pub fn compress_reader(
+    mut reader: impl Read,
+    settings: Settings,
+    head_size: usize,
+    mut output: impl FnMut(Vec<u8>) -> io::Result<()>,
+) -> io::Result<Compressed> {
+    let buffer_size = settings.buffer_size.max(1);
+    let mut chunks: Vec<Vec<u8>> = (0..settings.threads.max(1))
+        .map(|_| Vec::with_capacity(buffer_size))
+        .collect();
+    let mut compressed = Compressed {
+        size: 0,
+        head: Vec::new(),
+        peak_memory: 0,
+    };
+
+    loop {
+        let mut filled = 0;
+        for chunk in &mut chunks {
+            chunk.clear();
+            (&mut reader).take(buffer_size as u64).read_to_end(chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+            filled += 1;
+            if chunk.len() < buffer_size {
+                break;
+            }
+        }
+        // An empty input still has to be a valid gzip file
+        if filled == 0 && compressed.size > 0 {
+            break;
+        }
+        let chunks = &chunks[..filled.max(1)];
+
+        for chunk in chunks {
+            let missing = head_size.saturating_sub(compressed.head.len());
+            compressed
+                .head
+                .extend_from_slice(&chunk[..missing.min(chunk.len())]);
+            compressed.size += chunk.len() as u64;
+        }
+        let members = compress_chunks(chunks, settings.level)?;
+        let held = chunks.iter().map(Vec::capacity).sum::<usize>()
+            + members.iter().map(Vec::capacity).sum::<usize>();
+        compressed.peak_memory = compressed.peak_memory.max(held);
+        for member in members {
+            output(member)?;
+        }
+
+        if chunks.last().is_none_or(|chunk| chunk.len() < buffer_size) {
+            break;
+        }
+    }
+    compressed.peak_memory = compressed
+        .peak_memory
+        .max(chunks.iter().map(Vec::capacity).sum::<usize>());
+
+    Ok(compressed)
+}
+
+/// Compress every chunk into a gzip member, each on its own thread
+fn compress_chunks(chunks: &[Vec<u8>], level: u32) -> io::Result<Vec<Vec<u8>>> {
+    if let [chunk] = chunks {
+        return Ok(vec![compress(chunk, level)?]);
+    }
+    std::thread::scope(|scope| {
+        let threads: Vec<_> = chunks
+            .iter()
+            .map(|chunk| scope.spawn(move || compress(chunk, level)))
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("Compression thread panicked")))
+            })
+            .collect()
+    })
+}
+
+/// Compress data into a single gzip member at `level` (0-9)
+pub fn compress(data: &[u8], level: u32) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Decompress a gzip compressed backup of one or more members into `output`
+///
+/// Returns the number of bytes written.
+pub fn decompress_into(input: impl Read, output: &mut impl Write) -> io::Result<u64> {
+    io::copy(&mut MultiGzDecoder::new(input), output)
+}
+
+/// Decompress the start of a gzip compressed backup
+///
+/// `data` may be cut anywhere, everything up to the cut is returned.
+pub fn decompress_prefix(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoder = MultiGzDecoder::new(data);
+    let mut decoded = Vec::new();
+    let mut buffer = [0u8; 8192];
+    loop {
+        match decoder.read(&mut buffer) {
+            Ok(0) => return Ok(decoded),
+            Ok(n) => decoded.extend_from_slice(&buffer[..n]),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(decoded),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Extension of a backup object, with the ".gz" suffix when compressed
+pub fn stored_extension(extension: &str, compressed: bool) -> String {
+    if compressed {
+        format!("{}{}", extension, GZIP_SUFFIX)
+    } else {
+        extension.to_string()
+    }
+}
+
+/// Whether an object key is a compressed backup
+pub fn is_compressed(key: &str) -> bool {
+    key.ends_with(GZIP_SUFFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        decompress_into(data, &mut decoded)?;
+        Ok(decoded)
+    }
+
+    fn sample() -> Vec<u8> {
+        (0..100_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect()
+    }
+
+    /// Compress `data` with `compress_reader`, returning the members concatenated
+    fn compress_all(data: &[u8], settings: Settings, head_size: usize) -> (Vec<u8>, Compressed) {
+        let mut output = Vec::new();
+        let compressed = compress_reader(data, settings, head_size, |member| {
+            output.extend_from_slice(&member);
+            Ok(())
+        })
+        .unwrap();
+        (output, compressed)
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = sample();
+        for level in [0, 1, 6, 9] {
+            let compressed = compress(&data, level).unwrap();
+            assert_eq!(decompress(&compressed).unwrap(), data);
+        }
+        assert!(compress(&data, 9).unwrap().len() < data.len() / 4);
+    }
+
+    #[test]
+    fn prefix_of_a_truncated_backup() {
+        let data = sample();
+        let compressed = compress(&data, 6).unwrap();
+        let decoded = decompress_prefix(&compressed[..compressed.len() / 2]).unwrap();
+        assert!(!decoded.is_empty());
+        assert_eq!(decoded[..], data[..decoded.len()]);
+        assert_eq!(decompress_prefix(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn chunks_are_compressed_into_members() {
+        let data = sample();
+        for threads in [1, 3] {
+            let settings = Settings {
+                level: 6,
+                buffer_size: 64 * 1024,
+                threads,
+            };
+            let (output, compressed) = compress_all(&data, settings, 9);
+            assert_eq!(compressed.size, data.len() as u64);
+            assert_eq!(compressed.head, data[..9]);
+            assert_eq!(decompress(&output).unwrap(), data);
+            // 400000 bytes are 7 members, each starting with the gzip magic
+            let members = output
+                .windows(3)
+                .filter(|w| w == &[0x1f, 0x8b, 0x08])
+                .count();
+            assert!(members >= 7, "{} members", members);
+            assert!(
+                compressed.peak_memory < data.len(),
+                "{} bytes held",
+                compressed.peak_memory
+            );
+        }
+    }
+
+    #[test]
+    fn empty_input_is_a_valid_gzip_file() {
+        let settings = Settings {
+            level: 9,
+            buffer_size: 1024,
+            threads: 2,
+        };
+        let (output, compressed) = compress_all(b"", settings, 9);
+        assert_eq!(compressed.size, 0);
+        assert!(compressed.head.is_empty());
+        assert!(decompress(&output).unwrap().is_empty());
+
+        // Exactly one chunk does not leave an empty member behind
+        let (output, _) = compress_all(&[7u8; 1024], settings, 0);
+        assert_eq!(decompress(&output).unwrap(), [7u8; 1024]);
+    }
+
+    #[test]
+    fn output_errors_stop_the_compression() {
+        let settings = Settings {
+            level: 1,
+            buffer_size: 1024,
+            threads: 1,
+        };
+        let mut members = 0;
+        let result = compress_reader(&sample()[..], settings, 0, |_| {
+            members += 1;
+            Err(io::Error::other("Upload stopped"))
+        });
+        assert_eq!(result.unwrap_err().to_string(), "Upload stopped");
+        assert_eq!(members, 1);
+    }
+
+    #[test]
+    fn corrupt_data_is_an_error() {
+        assert!(decompress(b"REDIS0011").is_err());
+    }
+
+    #[test]
+    fn extension_of_compressed_backups() {
+        assert_eq!(stored_extension("rdb", true), "rdb.gz");
+        assert_eq!(stored_extension("aof.tar", false), "aof.tar");
+        assert!(is_compressed("node-1/2026/10/14/120000.aof.tar.gz"));
+        assert!(!is_compressed("node-1/2026/10/14/120000.rdb"));
+    }
+}
@@ -0,0 +1,711 @@
+//! Persistence files to back up
+//!
+//! This module locates the files Redis persists to, depending on the backup mode:
+//! the RDB dump, a single append only file, or the multi part AOF directory of
+//! Redis 7+. Multiple files are bundled into a tar archive, so a restore always
+//! gets a consistent set. A `dump_filename` glob matches one dump per shard, each
+//! backed up on its own.
+
+use anyhow::{Context, Result, bail};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll, ready};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tracing::{debug, warn};
+
+use crate::config::{BackupConfig, BackupMode};
+
+/// Size of a tar header and of the blocks file contents are padded to
+const TAR_BLOCK: usize = 512;
+
+/// Largest file size that fits into the octal size field of a tar header
+const TAR_MAX_OCTAL_SIZE: u64 = 0o77_777_777_777;
+
+/// Times the files are re-statted before giving up on them settling
+const SETTLE_ATTEMPTS: usize = 5;
+
+/// Contents of a backup and their SHA-256 digest, computed while reading them
+pub struct BackupData {
+    pub data: Vec<u8>,
+    /// Lowercase hex digest, as in the checksum sidecar
+    pub checksum: String,
+}
+
+/// Lowercase hex SHA-256 of `data`, as printed by `sha256sum`
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+/// Lowercase hex of a digest
+pub fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Reader hashing the bytes passing through it, so a file is read only once
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Lowercase hex digest of everything read so far
+    pub fn checksum(self) -> String {
+        hex(&self.hasher.finalize())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.hasher.update(&buf.filled()[start..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: io::Read> io::Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// The files making up one backup
+pub struct BackupFiles {
+    /// Paths and their names inside the archive
+    files: Vec<(PathBuf, String)>,
+    /// Extension of the backup object key, e.g. "rdb"
+    pub extension: &'static str,
+    /// Name of the dump file without extension, if matched by a `dump_filename` glob
+    pub shard: Option<String>,
+}
+
+impl BackupFiles {
+    /// Locate the files to back up in `data_path`, one set per shard
+    ///
+    /// Returns the path that was expected if there is nothing to back up.
+    pub async fn locate(
+        data_path: &Path,
+        config: &BackupConfig,
+    ) -> Result<Result<Vec<Self>, PathBuf>> {
+        if config.mode == BackupMode::Rdb && is_glob(&config.dump_filename) {
+            let mut shards = Vec::new();
+            let mut entries = fs::read_dir(data_path)
+                .await
+                .with_context(|| format!("Failed to read data path {:?}", data_path))?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if shard_match(&config.dump_filename, &name) && entry.file_type().await?.is_file() {
+                    let shard = Path::new(&name)
+                        .file_stem()
+                        .map(|stem| stem.to_string_lossy().into_owned());
+                    shards.push(BackupFiles {
+                        files: vec![(entry.path(), name)],
+                        extension: "rdb",
+                        shard,
+                    });
+                }
+            }
+            if shards.is_empty() {
+                return Ok(Err(data_path.join(&config.dump_filename)));
+            }
+            shards.sort_by(|a, b| a.shard.cmp(&b.shard));
+            return Ok(Ok(shards));
+        }
+
+        if config.mode == BackupMode::Rdb {
+            let path = dump_path(data_path, config);
+            if !path.exists() {
+                return Ok(Err(path));
+            }
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| config.dump_filename.clone());
+            return Ok(Ok(vec![BackupFiles {
+                files: vec![(path, name)],
+                extension: "rdb",
+                shard: None,
+            }]));
+        }
+
+        // Redis 7+ keeps a manifest listing the base and incremental files
+        let dir = data_path.join(&config.aof_dirname);
+        let manifest_name = format!("{}.manifest", config.aof_filename);
+        let manifest = dir.join(&manifest_name);
+        if manifest.exists() {
+            let content = fs::read_to_string(&manifest)
+                .await
+                .with_context(|| format!("Failed to read AOF manifest {:?}", manifest))?;
+
+            let mut files = vec![(
+                manifest.clone(),
+                format!("{}/{}", config.aof_dirname, manifest_name),
+            )];
+            for name in manifest_files(&content) {
+                files.push((dir.join(&name), format!("{}/{}", config.aof_dirname, name)));
+            }
+
+            return Ok(Ok(vec![BackupFiles {
+                files,
+                extension: "aof.tar",
+                shard: None,
+            }]));
+        }
+
+        // Before Redis 7 the AOF is a single file
+        let path = data_path.join(&config.aof_filename);
+        if !path.exists() {
+            return Ok(Err(dir));
+        }
+        Ok(Ok(vec![BackupFiles {
+            files: vec![(path, config.aof_filename.clone())],
+            extension: "aof",
+            shard: None,
+        }]))
+    }
+
+    /// The file of a backup that is stored as is, not bundled into an archive
+    pub fn single_file(&self) -> Option<&Path> {
+        match self.files.as_slice() {
+            [(path, _)] => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Total size of the files
+    pub async fn size(&self) -> Result<u64> {
+        let mut size = 0;
+        for (path, _) in &self.files {
+            size += fs::metadata(path).await?.len();
+        }
+        Ok(size)
+    }
+
+    /// Time the newest file was last modified
+    pub async fn modified(&self) -> Result<SystemTime> {
+        let mut modified = SystemTime::UNIX_EPOCH;
+        for (path, _) in &self.files {
+            modified = modified.max(fs::metadata(path).await?.modified()?);
+        }
+        Ok(modified)
+    }
+
+    /// Flush the files and wait until their size and modification time stop changing
+    ///
+    /// On overlay and network filesystems a dump just written by Redis may not be
+    /// fully visible yet. Every file is synced, then re-statted after `delay`.
+    pub async fn settle(&self, delay: Duration) -> Result<()> {
+        let mut previous = self.stat().await?;
+        for _ in 0..SETTLE_ATTEMPTS {
+            for (path, _) in &self.files {
+                // Not every filesystem supports syncing, re-statting still applies
+                let synced = match fs::File::open(path).await {
+                    Ok(file) => file.sync_all().await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = synced {
+                    warn!("Failed to sync {:?}: {}", path, e);
+                }
+            }
+
+            tokio::time::sleep(delay).await;
+            let current = self.stat().await?;
+            if current == previous {
+                return Ok(());
+            }
+            debug!("Backup files still changing, checking again in {:?}", delay);
+            previous = current;
+        }
+        bail!(
+            "Backup files still changing after {} checks {:?} apart",
+            SETTLE_ATTEMPTS,
+            delay
+        )
+    }
+
+    /// Size and modification time of every file
+    async fn stat(&self) -> Result<Vec<(u64, SystemTime)>> {
+        let mut stats = Vec::new();
+        for (path, _) in &self.files {
+            let metadata = fs::metadata(path)
+                .await
+                .with_context(|| format!("Failed to stat {:?}", path))?;
+            stats.push((metadata.len(), metadata.modified()?));
+        }
+        Ok(stats)
+    }
+
+    /// Delete the files, unless they changed since `modified` and `size` were taken
+    ///
+    /// Returns whether the files were deleted. A dump Redis saved again in the
+    /// meantime has not been backed up yet and is kept.
+    pub async fn remove_unchanged(&self, modified: SystemTime, size: u64) -> Result<bool> {
+        if self.modified().await? != modified || self.size().await? != size {
+            return Ok(false);
+        }
+        for (path, _) in &self.files {
+            fs::remove_file(path)
+                .await
+                .with_context(|| format!("Failed to delete {:?}", path))?;
+        }
+        Ok(true)
+    }
+
+    /// Paths of the files
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|(path, _)| path.as_path())
+    }
+
+    /// Read the backup, bundling multiple files into a tar archive
+    ///
+    /// The digest is computed as the file is read, or as the archive is assembled,
+    /// instead of in a second pass over the whole backup.
+    pub async fn read(&self) -> Result<BackupData> {
+        if let [(path, _)] = self.files.as_slice() {
+            let file = fs::File::open(path)
+                .await
+                .with_context(|| format!("Failed to read {:?}", path))?;
+            let size = file.metadata().await?.len();
+            let mut reader = HashingReader::new(file);
+            let mut data = Vec::with_capacity(usize::try_from(size).unwrap_or(0));
+            reader
+                .read_to_end(&mut data)
+                .await
+                .with_context(|| format!("Failed to read {:?}", path))?;
+            return Ok(BackupData {
+                data,
+                checksum: reader.checksum(),
+            });
+        }
+
+        let mut archive = Vec::new();
+        let mut hasher = Sha256::new();
+        for (path, name) in &self.files {
+            let data = fs::read(path)
+                .await
+                .with_context(|| format!("Failed to read {:?}", path))?;
+            let modified = fs::metadata(path).await?.modified()?;
+            let start = archive.len();
+            append_tar_entry(&mut archive, name, &data, modified)?;
+            hasher.update(&archive[start..]);
+        }
+
+        // The archive ends with two empty blocks
+        let start = archive.len();
+        archive.resize(start + 2 * TAR_BLOCK, 0);
+        hasher.update(&archive[start..]);
+        Ok(BackupData {
+            data: archive,
+            checksum: hex(&hasher.finalize()),
+        })
+    }
+}
+
+/// Path of a single RDB dump, `dump_path` if set or else `dump_filename` in `data_path`
+pub fn dump_path(data_path: &Path, config: &BackupConfig) -> PathBuf {
+    match &config.dump_path {
+        Some(path) => path.clone(),
+        None => data_path.join(&config.dump_filename),
+    }
+}
+
+/// Whether a `dump_filename` is a glob pattern rather than a single file name
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Temporary files Redis writes while saving, never a complete dump
+const TEMP_FILES: [&str; 2] = ["temp-*.rdb", "temp-*.aof"];
+
+/// Whether a file in the data path is a shard matched by a `dump_filename` glob
+///
+/// Temporary files of a running BGSAVE or AOF rewrite are skipped, even if the glob
+/// matches them.
+fn shard_match(pattern: &str, name: &str) -> bool {
+    glob_match(pattern, name) && !TEMP_FILES.iter().any(|temp| glob_match(temp, name))
+}
+
+/// Match a file name against a glob, `*` matching any run of characters and `?` one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Backtrack to the last `*` on a mismatch, letting it match one more character
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// File names listed in an AOF manifest
+///
+/// Every line describes one file as key value pairs, e.g.
+/// `file appendonly.aof.1.base.rdb seq 1 type b`.
+fn manifest_files(manifest: &str) -> Vec<String> {
+    manifest
+        .lines()
+        .filter_map(|line| {
+            let mut tokens = line.split_whitespace();
+            while let Some(key) = tokens.next() {
+                let value = tokens.next()?;
+                if key == "file" {
+                    return Some(value.trim_matches('"').to_string());
+                }
+            }
+            None
+        })
+        .collect()
+}
+
+/// Append a regular file in ustar format
+fn append_tar_entry(
+    archive: &mut Vec<u8>,
+    name: &str,
+    data: &[u8],
+    modified: SystemTime,
+) -> Result<()> {
+    if name.len() > 100 {
+        anyhow::bail!("File name too long for a tar archive: {}", name);
+    }
+
+    let mut header = [0u8; TAR_BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], 0o644);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+
+    // Sizes beyond the octal field use the GNU base-256 encoding
+    let size = data.len() as u64;
+    if size <= TAR_MAX_OCTAL_SIZE {
+        write_octal(&mut header[124..136], size);
+    } else {
+        header[124] = 0x80;
+        header[128..136].copy_from_slice(&size.to_be_bytes());
+    }
+
+    let mtime = modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    write_octal(&mut header[136..148], mtime);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|b| u32::from(*b)).sum();
+    write_octal(&mut header[148..155], u64::from(checksum));
+
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    let padding = (TAR_BLOCK - data.len() % TAR_BLOCK) % TAR_BLOCK;
+    archive.resize(archive.len() + padding, 0);
+
+    Ok(())
+}
+
+/// Write a zero padded, NUL terminated octal number filling `field`
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::get_default_config;
+    use crate::testing::TempDir;
+    use std::time::Duration;
+
+    /// Value of an octal header field, up to its first NUL or space
+    fn octal_field(field: &[u8]) -> u64 {
+        let digits = field.split(|&b| b == 0 || b == b' ').next().unwrap();
+        u64::from_str_radix(std::str::from_utf8(digits).unwrap(), 8).unwrap()
+    }
+
+    fn modified() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_733_063_422)
+    }
+
+    #[test]
+    fn tar_entry_header() {
+        let mut archive = Vec::new();
+        append_tar_entry(
+            &mut archive,
+            "appendonlydir/appendonly.aof.1.incr.aof",
+            b"SET",
+            modified(),
+        )
+        .unwrap();
+        let header = &archive[..TAR_BLOCK];
+
+        assert_eq!(&header[..39], b"appendonlydir/appendonly.aof.1.incr.aof");
+        assert!(header[39..100].iter().all(|&b| b == 0));
+        assert_eq!(octal_field(&header[100..108]), 0o644);
+        assert_eq!(octal_field(&header[124..136]), 3);
+        assert_eq!(octal_field(&header[136..148]), 1_733_063_422);
+        assert_eq!(header[156], b'0');
+        assert_eq!(&header[257..263], b"ustar\0");
+        assert_eq!(&header[263..265], b"00");
+
+        // The checksum covers the header with its own field taken as spaces
+        let checksum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    u64::from(b' ')
+                } else {
+                    u64::from(b)
+                }
+            })
+            .sum();
+        assert_eq!(octal_field(&header[148..156]), checksum);
+        assert_eq!(header[154], 0);
+    }
+
+    #[test]
+    fn tar_entry_name_limit() {
+        let mut archive = Vec::new();
+        append_tar_entry(&mut archive, &"a".repeat(100), b"", modified()).unwrap();
+        assert_eq!(&archive[..100], "a".repeat(100).as_bytes());
+
+        let mut archive = Vec::new();
+        assert!(append_tar_entry(&mut archive, &"a".repeat(101), b"", modified()).is_err());
+        assert!(archive.is_empty());
+    }
+
+    #[test]
+    fn tar_entry_padding() {
+        for (size, blocks) in [(0, 1), (1, 2), (511, 2), (512, 2), (513, 3)] {
+            let mut archive = Vec::new();
+            let data = vec![b'x'; size];
+            append_tar_entry(&mut archive, "file", &data, modified()).unwrap();
+
+            assert_eq!(archive.len(), blocks * TAR_BLOCK, "{}", size);
+            assert_eq!(&archive[TAR_BLOCK..TAR_BLOCK + size], data.as_slice());
+            assert!(archive[TAR_BLOCK + size..].iter().all(|&b| b == 0));
+        }
+    }
+
+    #[tokio::test]
+    async fn read_bundles_multi_part_aof() {
+        let data_path = TempDir::new("aof");
+        let dir = data_path.join("appendonlydir");
+        fs::create_dir_all(&dir).await.unwrap();
+        let manifest = concat!(
+            "file appendonly.aof.1.base.rdb seq 1 type b\n",
+            "file appendonly.aof.1.incr.aof seq 1 type i\n",
+        );
+        fs::write(dir.join("appendonly.aof.manifest"), manifest)
+            .await
+            .unwrap();
+        fs::write(dir.join("appendonly.aof.1.base.rdb"), vec![b'r'; 600])
+            .await
+            .unwrap();
+        fs::write(
+            dir.join("appendonly.aof.1.incr.aof"),
+            b"*1\r\n$4\r\nPING\r\n",
+        )
+        .await
+        .unwrap();
+
+        let mut config = get_default_config().backup;
+        config.mode = BackupMode::Aof;
+        let files = BackupFiles::locate(&data_path, &config)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(files[0].extension, "aof.tar");
+        let BackupData {
+            data: archive,
+            checksum,
+        } = files[0].read().await.unwrap();
+        assert_eq!(checksum, sha256_hex(&archive));
+
+        // Manifest, base and increment, each with their padding, and two empty blocks
+        assert_eq!(archive.len(), (2 + 3 + 2 + 2) * TAR_BLOCK);
+        assert!(
+            archive[archive.len() - 2 * TAR_BLOCK..]
+                .iter()
+                .all(|&b| b == 0)
+        );
+
+        // The system tar, where available, reads the archive back
+        let path = data_path.join("backup.aof.tar");
+        fs::write(&path, &archive).await.unwrap();
+        if let Ok(output) = std::process::Command::new("tar")
+            .arg("-tvf")
+            .arg(&path)
+            .output()
+        {
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            let listing = String::from_utf8_lossy(&output.stdout);
+            let entries: Vec<(&str, &str)> = listing
+                .lines()
+                .map(|line| {
+                    let fields: Vec<&str> = line.split_whitespace().collect();
+                    (fields[2], fields[fields.len() - 1])
+                })
+                .collect();
+            assert_eq!(
+                entries,
+                [
+                    (
+                        manifest.len().to_string().as_str(),
+                        "appendonlydir/appendonly.aof.manifest"
+                    ),
+                    ("600", "appendonlydir/appendonly.aof.1.base.rdb"),
+                    ("14", "appendonlydir/appendonly.aof.1.incr.aof"),
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn hashing_reader_hashes_what_it_reads() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let mut reader = HashingReader::new(&data[..]);
+        let mut head = [0u8; 100];
+        reader.read_exact(&mut head).await.unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+
+        assert_eq!([&head[..], &rest].concat(), data);
+        assert_eq!(reader.checksum(), sha256_hex(&data));
+        assert_eq!(
+            HashingReader::new(&b""[..]).checksum(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[tokio::test]
+    async fn read_hashes_single_dump() {
+        let data_path = TempDir::new("rdb");
+        let dump = vec![b'r'; 70_000];
+        fs::write(data_path.join("dump.rdb"), &dump).await.unwrap();
+
+        let config = get_default_config().backup;
+        let files = BackupFiles::locate(&data_path, &config)
+            .await
+            .unwrap()
+            .unwrap();
+        let BackupData { data, checksum } = files[0].read().await.unwrap();
+        assert_eq!(data, dump);
+        assert_eq!(checksum, sha256_hex(&dump));
+    }
+
+    #[test]
+    fn glob_match_star() {
+        assert!(glob_match("dump-*.rdb", "dump-0.rdb"));
+        assert!(glob_match("dump-*.rdb", "dump-.rdb"));
+        assert!(glob_match("dump-*.rdb", "dump-a.b.rdb"));
+        assert!(glob_match("*", "dump.rdb"));
+        assert!(glob_match("*.rdb", ".rdb"));
+        assert!(!glob_match("dump-*.rdb", "dump.rdb"));
+        assert!(!glob_match("dump-*.rdb", "temp-dump-0.rdb"));
+    }
+
+    #[test]
+    fn shard_match_skips_temporary_files() {
+        assert!(glob_match("*.rdb", "temp-123.rdb"));
+        assert!(!shard_match("*.rdb", "temp-123.rdb"));
+        assert!(!shard_match("*", "temp-rewriteaof-123.aof"));
+        assert!(!shard_match("temp-*.rdb", "temp-123.rdb"));
+        assert!(shard_match("*.rdb", "dump-0.rdb"));
+        assert!(shard_match("*.rdb", "temp.rdb"));
+    }
+
+    #[tokio::test]
+    async fn locate_skips_temporary_dumps() {
+        let data_path = TempDir::new("shards");
+        fs::write(data_path.join("dump-0.rdb"), b"REDIS0011")
+            .await
+            .unwrap();
+        fs::write(data_path.join("temp-123.rdb"), b"REDIS")
+            .await
+            .unwrap();
+
+        let mut config = get_default_config().backup;
+        config.dump_filename = "*.rdb".to_string();
+        let shards = BackupFiles::locate(&data_path, &config)
+            .await
+            .unwrap()
+            .unwrap();
+        let shards: Vec<_> = shards.iter().map(|files| files.shard.clone()).collect();
+        assert_eq!(shards, [Some("dump-0".to_string())]);
+    }
+
+    #[test]
+    fn glob_match_question_mark() {
+        assert!(glob_match("dump-?.rdb", "dump-1.rdb"));
+        assert!(glob_match("dump-?.rdb", "dump-é.rdb"));
+        assert!(!glob_match("dump-?.rdb", "dump-10.rdb"));
+        assert!(!glob_match("dump-?.rdb", "dump-.rdb"));
+    }
+
+    #[test]
+    fn glob_match_trailing_star() {
+        assert!(glob_match("dump*", "dump"));
+        assert!(glob_match("dump*", "dump-1.rdb"));
+        assert!(glob_match("dump-**", "dump-1"));
+        assert!(!glob_match("dump*", "dum"));
+    }
+
+    #[test]
+    fn glob_match_backtracks() {
+        assert!(!glob_match("dump-*.rdb", "dump-a.rdb.tmp"));
+        assert!(glob_match("dump-*.rdb", "dump-a.rdb.rdb"));
+        assert!(glob_match("*-*.rdb", "a-b-c.rdb"));
+        assert!(glob_match("d*p*.rdb", "dump-dump.rdb"));
+        assert!(!glob_match("*a*b", "aaaa"));
+    }
+
+    #[test]
+    fn is_glob_patterns() {
+        assert!(is_glob("dump-*.rdb"));
+        assert!(is_glob("dump-?.rdb"));
+        assert!(!is_glob("dump.rdb"));
+    }
+}
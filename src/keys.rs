@@ -0,0 +1,450 @@
+//! Backup object keys
+//!
+//! Keys below the storage prefix are rendered from `backup.key_template`. The same
+//! template parses them back, so listing and retention find the backups of a node
+//! and their timestamps whatever the naming scheme.
+//!
+//! The node of a cluster master is followed by the slot ranges it serves, e.g.
+//! `redis-0-slots-0-5460`, so a restore can pick one backup per slot range. Keys of
+//! a node are matched with and without slot ranges, so retention still finds the
+//! backups of a node taken before resharding.
+
+use crate::dump::sha256_hex;
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+
+/// Extensions of backup object keys, longest first so `aof.tar` is not taken for `aof`
+const EXTENSIONS: &[&str] = &["aof.tar.gz", "aof.tar", "rdb.gz", "aof.gz", "rdb", "aof"];
+
+// Layout of the time placeholders, `d` standing for a digit
+const DATE_FORMAT: &str = "%Y/%m/%d";
+const DATE_LAYOUT: &str = "dddd/dd/dd";
+const TIME_FORMAT: &str = "%H%M%S";
+const TIME_LAYOUT: &str = "dddddd";
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+const TIMESTAMP_LAYOUT: &str = "dddd-dd-ddTdd:dd:ddZ";
+
+/// Separates the node from its slot ranges
+const SLOTS_SEPARATOR: &str = "-slots-";
+
+/// Hex digits of the node hash, spreading nodes over 65536 prefixes
+const HASH_LENGTH: usize = 4;
+
+/// Literal text or placeholder of a key template
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Node,
+    Hash,
+    Date,
+    Time,
+    Timestamp,
+    Ext,
+}
+
+/// Parsed `backup.key_template`
+#[derive(Debug, Clone)]
+pub struct KeyTemplate {
+    parts: Vec<Part>,
+}
+
+/// Time placeholders found while matching a key
+#[derive(Default)]
+struct Fields<'a> {
+    date: Option<&'a str>,
+    time: Option<&'a str>,
+    timestamp: Option<&'a str>,
+}
+
+impl KeyTemplate {
+    /// Parse a template like `{node}_{timestamp}.{ext}`
+    ///
+    /// The template must name the node, contain `{timestamp}` or both `{date}` and
+    /// `{time}`, and end with `.{ext}`, so every key identifies its backup.
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| "unclosed placeholder".to_string())?
+                + start;
+            parts.push(match &rest[start + 1..end] {
+                "node" => Part::Node,
+                "hash" => Part::Hash,
+                "date" => Part::Date,
+                "time" => Part::Time,
+                "timestamp" => Part::Timestamp,
+                "ext" => Part::Ext,
+                other => return Err(format!("unknown placeholder {{{}}}", other)),
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        if !parts.contains(&Part::Node) {
+            return Err("must contain {node}".to_string());
+        }
+        let has_time = parts.contains(&Part::Timestamp)
+            || (parts.contains(&Part::Date) && parts.contains(&Part::Time));
+        if !has_time {
+            return Err("must contain {timestamp}, or {date} and {time}".to_string());
+        }
+        if !matches!(parts.as_slice(), [.., Part::Literal(dot), Part::Ext] if dot.ends_with('.')) {
+            return Err("must end with .{ext}".to_string());
+        }
+
+        Ok(KeyTemplate { parts })
+    }
+
+    /// Key of a backup of `node` with the given time and extension
+    ///
+    /// `slots` are the slot ranges of a cluster master joined by `_`, following the
+    /// node name. The hash is that of the node name alone.
+    pub fn render(
+        &self,
+        node: &str,
+        slots: Option<&str>,
+        time: DateTime<Utc>,
+        extension: &str,
+    ) -> String {
+        let mut key = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => key.push_str(literal),
+                Part::Node => {
+                    key.push_str(node);
+                    if let Some(slots) = slots {
+                        key.push_str(SLOTS_SEPARATOR);
+                        key.push_str(slots);
+                    }
+                }
+                Part::Hash => key.push_str(&node_hash(node)),
+                Part::Date => key.push_str(&time.format(DATE_FORMAT).to_string()),
+                Part::Time => key.push_str(&time.format(TIME_FORMAT).to_string()),
+                Part::Timestamp => key.push_str(&time.format(TIMESTAMP_FORMAT).to_string()),
+                Part::Ext => key.push_str(extension),
+            }
+        }
+        key
+    }
+
+    /// Start shared by all keys of `node`, up to the node name or the first time
+    /// placeholder, whichever comes first
+    ///
+    /// The node name may be followed by slot ranges, so nothing after it is shared.
+    pub fn prefix(&self, node: &str) -> String {
+        let mut prefix = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => prefix.push_str(literal),
+                Part::Node => {
+                    prefix.push_str(node);
+                    break;
+                }
+                Part::Hash => prefix.push_str(&node_hash(node)),
+                _ => break,
+            }
+        }
+        prefix
+    }
+
+    /// Whether keys start with the node name, after its hash if any, so listing by
+    /// prefix only finds its keys
+    pub fn starts_with_node(&self) -> bool {
+        let parts = match self.parts.as_slice() {
+            [Part::Hash, Part::Literal(_), parts @ ..] | [Part::Hash, parts @ ..] => parts,
+            parts => parts,
+        };
+        parts.first() == Some(&Part::Node)
+    }
+
+    /// Time encoded in `key` if it matches this template, for `node` if given
+    pub fn timestamp(&self, key: &str, node: Option<&str>) -> Option<DateTime<Utc>> {
+        let mut fields = Fields::default();
+        if !match_parts(&self.parts, key, node, &mut fields) {
+            return None;
+        }
+
+        if let Some(timestamp) = fields.timestamp {
+            return DateTime::parse_from_rfc3339(timestamp)
+                .ok()
+                .map(|t| t.with_timezone(&Utc));
+        }
+        let date = NaiveDate::parse_from_str(fields.date?, DATE_FORMAT).ok()?;
+        let time = NaiveTime::parse_from_str(fields.time?, TIME_FORMAT).ok()?;
+        Some(date.and_time(time).and_utc())
+    }
+}
+
+/// Match `key` against the remaining template parts, backtracking over node names
+fn match_parts<'a>(
+    parts: &[Part],
+    key: &'a str,
+    node: Option<&str>,
+    fields: &mut Fields<'a>,
+) -> bool {
+    let Some((part, rest)) = parts.split_first() else {
+        return key.is_empty();
+    };
+
+    match part {
+        Part::Literal(literal) => key
+            .strip_prefix(literal.as_str())
+            .is_some_and(|key| match_parts(rest, key, node, fields)),
+        Part::Node => match node {
+            Some(node) => key.strip_prefix(node).is_some_and(|key| {
+                match_parts(rest, key, Some(node), fields)
+                    || key
+                        .strip_prefix(SLOTS_SEPARATOR)
+                        .is_some_and(|key| match_slots(rest, key, node, fields))
+            }),
+            // Any node name, which never spans a path segment
+            None => (1..=key.len())
+                .filter(|&end| key.is_char_boundary(end))
+                .take_while(|&end| !key[..end].contains('/'))
+                .any(|end| match_parts(rest, &key[end..], None, fields)),
+        },
+        Part::Hash => {
+            let Some((hash, key)) = key.split_at_checked(HASH_LENGTH) else {
+                return false;
+            };
+            let matches = match node {
+                Some(node) => hash == node_hash(node),
+                None => hash.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')),
+            };
+            matches && match_parts(rest, key, node, fields)
+        }
+        Part::Date => match_layout(key, DATE_LAYOUT).is_some_and(|(date, key)| {
+            fields.date = Some(date);
+            match_parts(rest, key, node, fields)
+        }),
+        Part::Time => match_layout(key, TIME_LAYOUT).is_some_and(|(time, key)| {
+            fields.time = Some(time);
+            match_parts(rest, key, node, fields)
+        }),
+        Part::Timestamp => match_layout(key, TIMESTAMP_LAYOUT).is_some_and(|(timestamp, key)| {
+            fields.timestamp = Some(timestamp);
+            match_parts(rest, key, node, fields)
+        }),
+        Part::Ext => EXTENSIONS.iter().any(|extension| {
+            key.strip_prefix(extension)
+                .is_some_and(|key| match_parts(rest, key, node, fields))
+        }),
+    }
+}
+
+/// Match slot ranges like `0-100_5461-10922` and the remaining template parts
+fn match_slots<'a>(parts: &[Part], key: &'a str, node: &str, fields: &mut Fields<'a>) -> bool {
+    key.starts_with(|c: char| c.is_ascii_digit())
+        && (1..=key.len())
+            .take_while(|&end| {
+                key.as_bytes()[end - 1].is_ascii_digit()
+                    || matches!(key.as_bytes()[end - 1], b'-' | b'_')
+            })
+            .any(|end| match_parts(parts, &key[end..], Some(node), fields))
+}
+
+/// Short hash of `node`, so the keys of many nodes spread over the key space
+fn node_hash(node: &str) -> String {
+    let digest = sha256_hex(node.as_bytes());
+    digest[..HASH_LENGTH].to_string()
+}
+
+/// Split off the start of `key` if it follows `layout`, where `d` matches a digit
+fn match_layout<'a>(key: &'a str, layout: &str) -> Option<(&'a str, &'a str)> {
+    let head = key.get(..layout.len())?;
+    let matches = head.bytes().zip(layout.bytes()).all(|(c, l)| match l {
+        b'd' => c.is_ascii_digit(),
+        _ => c == l,
+    });
+    matches.then(|| key.split_at(layout.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn time() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 12, 1, 14, 30, 22).unwrap()
+    }
+
+    #[test]
+    fn parse_rejects_incomplete_templates() {
+        for template in [
+            "{timestamp}.{ext}",
+            "{node}.{ext}",
+            "{node}_{date}.{ext}",
+            "{node}_{timestamp}",
+            "{node}_{timestamp}.{ext}.gz",
+            "{node}_{timestamp}{ext}",
+            "{node}_{uuid}_{timestamp}.{ext}",
+            "{node_{timestamp}.{ext}",
+        ] {
+            assert!(KeyTemplate::parse(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn render_round_trips_timestamp() {
+        for template in [
+            "{node}_{timestamp}.{ext}",
+            "{node}/{date}/{time}.{ext}",
+            "{hash}/{node}/{timestamp}.{ext}",
+            "backups/{date}/{node}-{time}.{ext}",
+        ] {
+            let keys = KeyTemplate::parse(template).unwrap();
+            for extension in ["rdb", "aof", "aof.tar", "rdb.gz", "aof.tar.gz"] {
+                let key = keys.render("node-1", None, time(), extension);
+                assert_eq!(
+                    keys.timestamp(&key, Some("node-1")),
+                    Some(time()),
+                    "{}",
+                    key
+                );
+                assert_eq!(keys.timestamp(&key, None), Some(time()), "{}", key);
+            }
+        }
+    }
+
+    #[test]
+    fn timestamp_of_given_node_only() {
+        let keys = KeyTemplate::parse("{node}_{timestamp}.{ext}").unwrap();
+        let key = "node-10_2024-12-01T14:30:22Z.rdb";
+
+        assert_eq!(keys.timestamp(key, Some("node-10")), Some(time()));
+        assert_eq!(keys.timestamp(key, Some("node-1")), None);
+        assert_eq!(keys.timestamp(key, Some("node-100")), None);
+        assert_eq!(keys.timestamp(key, None), Some(time()));
+    }
+
+    #[test]
+    fn timestamp_of_any_node_stays_in_path_segment() {
+        let keys = KeyTemplate::parse("{node}_{timestamp}.{ext}").unwrap();
+
+        assert_eq!(
+            keys.timestamp("node_1_2024-12-01T14:30:22Z.rdb", None),
+            Some(time())
+        );
+        assert_eq!(
+            keys.timestamp("old/node-1_2024-12-01T14:30:22Z.rdb", None),
+            None
+        );
+    }
+
+    #[test]
+    fn timestamp_rejects_other_keys() {
+        let keys = KeyTemplate::parse("{node}_{timestamp}.{ext}").unwrap();
+
+        for key in [
+            "node-1_2024-12-01T14:30:22Z.rdb.sha256",
+            "node-1_2024-12-01T14:30:22Z.json",
+            "node-1_2024-12-01T14:30:22Z.tar",
+            "node-1_2024-12-01T14:30:2Z.rdb",
+            "node-1_2024-13-01T14:30:22Z.rdb",
+            "node-1.rdb",
+        ] {
+            assert_eq!(keys.timestamp(key, None), None, "{}", key);
+            assert_eq!(keys.timestamp(key, Some("node-1")), None, "{}", key);
+        }
+    }
+
+    #[test]
+    fn extension_prefers_aof_tar_over_aof() {
+        let keys = KeyTemplate::parse("{node}_{timestamp}.{ext}").unwrap();
+
+        assert_eq!(
+            keys.timestamp("node-1_2024-12-01T14:30:22Z.aof.tar", Some("node-1")),
+            Some(time())
+        );
+        assert_eq!(
+            keys.timestamp("node-1_2024-12-01T14:30:22Z.aof", Some("node-1")),
+            Some(time())
+        );
+        assert_eq!(
+            keys.timestamp("node-1_2024-12-01T14:30:22Z.aof.tar.gz", Some("node-1")),
+            Some(time())
+        );
+        assert_eq!(
+            keys.timestamp("node-1_2024-12-01T14:30:22Z.aof.tar.zst", Some("node-1")),
+            None
+        );
+    }
+
+    #[test]
+    fn hash_prefix_of_node() {
+        let keys = KeyTemplate::parse("{hash}/{node}_{timestamp}.{ext}").unwrap();
+        let key = keys.render("node-1", None, time(), "rdb");
+        let hash = node_hash("node-1");
+
+        assert_eq!(hash.len(), HASH_LENGTH);
+        assert_eq!(key, format!("{}/node-1_2024-12-01T14:30:22Z.rdb", hash));
+        assert_eq!(keys.prefix("node-1"), format!("{}/node-1", hash));
+        assert!(keys.starts_with_node());
+
+        // A key under another node's hash is not this node's
+        let moved = format!("{}/node-1_2024-12-01T14:30:22Z.rdb", node_hash("node-10"));
+        assert_eq!(keys.timestamp(&moved, Some("node-1")), None);
+        assert_eq!(keys.timestamp(&moved, None), Some(time()));
+        assert_eq!(
+            keys.timestamp("xyz!/node-1_2024-12-01T14:30:22Z.rdb", None),
+            None
+        );
+    }
+
+    #[test]
+    fn slot_ranges_follow_the_node() {
+        for template in [
+            "{node}_{timestamp}.{ext}",
+            "{hash}/{node}/{date}/{time}.{ext}",
+        ] {
+            let keys = KeyTemplate::parse(template).unwrap();
+            let key = keys.render("node-1", Some("0-100_5461-10922"), time(), "rdb");
+            assert!(key.contains("node-1-slots-0-100_5461-10922"), "{}", key);
+            assert_eq!(
+                keys.timestamp(&key, Some("node-1")),
+                Some(time()),
+                "{}",
+                key
+            );
+            assert_eq!(keys.timestamp(&key, None), Some(time()), "{}", key);
+            assert_eq!(
+                keys.timestamp(&key, Some("node-1-slots-0")),
+                None,
+                "{}",
+                key
+            );
+            assert!(key.starts_with(&keys.prefix("node-1")), "{}", key);
+        }
+
+        let keys = KeyTemplate::parse("{node}_{timestamp}.{ext}").unwrap();
+        assert_eq!(
+            keys.render("node-1", Some("0-5460"), time(), "rdb"),
+            "node-1-slots-0-5460_2024-12-01T14:30:22Z.rdb"
+        );
+        assert_eq!(
+            keys.timestamp("node-1-slots-_2024-12-01T14:30:22Z.rdb", Some("node-1")),
+            None
+        );
+        assert_eq!(
+            keys.timestamp("node-1-other_2024-12-01T14:30:22Z.rdb", Some("node-1")),
+            None
+        );
+    }
+
+    #[test]
+    fn prefix_up_to_first_time_placeholder() {
+        let keys = KeyTemplate::parse("{node}/{date}/{time}.{ext}").unwrap();
+        assert_eq!(keys.prefix("node-1"), "node-1");
+        assert!(keys.starts_with_node());
+
+        let keys = KeyTemplate::parse("backups/{date}/{node}-{time}.{ext}").unwrap();
+        assert_eq!(keys.prefix("node-1"), "backups/");
+        assert!(!keys.starts_with_node());
+    }
+}
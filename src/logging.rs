@@ -4,16 +4,84 @@
 //! It supports both text and JSON log formats and respects environment variables
 //! for controlling log levels.
 
-pub fn init_logging(level: &str, format: &str) {
+use anyhow::Result;
+use regex::Regex;
+use std::io::Write;
+use std::sync::Arc;
+
+/// Replacement for matches of the redaction patterns
+const REDACTED: &str = "[REDACTED]";
+
+/// Writer replacing matches of the redaction patterns in every log line
+///
+/// The formatter writes each event with a single `write_all`, so a pattern never
+/// spans two writes.
+struct RedactingWriter {
+    inner: Box<dyn Write>,
+    patterns: Arc<Vec<Regex>>,
+}
+
+impl Write for RedactingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut line = String::from_utf8_lossy(buf).into_owned();
+        for pattern in self.patterns.iter() {
+            if let std::borrow::Cow::Owned(redacted) = pattern.replace_all(&line, REDACTED) {
+                line = redacted;
+            }
+        }
+        self.inner.write_all(line.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Initialize logging, writing to stderr instead of stdout if `stderr` is set
+///
+/// Matches of `redact_patterns` are replaced with `[REDACTED]` in every log line.
+pub fn init_logging(
+    level: &str,
+    format: &str,
+    stderr: bool,
+    redact_patterns: &[String],
+) -> Result<()> {
+    let patterns = redact_patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern).map_err(|e| {
+                anyhow::anyhow!("Invalid logging.redact_patterns entry '{}': {}", pattern, e)
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let patterns = Arc::new(patterns);
+
     let env_filter = tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
         tracing_subscriber::EnvFilter::new("warn")
             .add_directive(format!("redis_vault={}", level).parse().unwrap())
     });
 
+    let writer = move || -> Box<dyn Write> {
+        let inner: Box<dyn Write> = if stderr {
+            Box::new(std::io::stderr())
+        } else {
+            Box::new(std::io::stdout())
+        };
+        if patterns.is_empty() {
+            return inner;
+        }
+        Box::new(RedactingWriter {
+            inner,
+            patterns: patterns.clone(),
+        })
+    };
+
     match format {
         "json" => {
             tracing_subscriber::fmt()
                 .with_env_filter(env_filter)
+                .with_writer(writer)
                 .json()
                 .flatten_event(true)
                 .without_time()
@@ -23,8 +91,11 @@ pub fn init_logging(level: &str, format: &str) {
         _ => {
             tracing_subscriber::fmt()
                 .with_env_filter(env_filter)
+                .with_writer(writer)
                 .with_target(false)
                 .init();
         }
     }
+
+    Ok(())
 }
@@ -1,18 +1,29 @@
 #![forbid(unsafe_code)]
 
 use anyhow::Result;
-use clap::Parser;
+use chrono::{DateTime, Utc};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 use git_version::git_version;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{debug, error, info};
+use tokio::sync::{RwLock, watch};
+use tracing::{debug, error, info, warn};
 
 mod backup;
+mod compression;
 mod config;
+mod dump;
+mod keys;
 mod logging;
 mod metrics;
+mod notify;
+mod rdb;
+mod restore;
+mod shutdown;
+mod state;
 mod storage;
+#[cfg(test)]
+mod testing;
 
 use backup::BackupManager;
 use config::load_config;
@@ -32,23 +43,256 @@ alongside Redis instances, providing automated backups to S3 or Google Cloud Sto
 with configurable retention policies."
 )]
 struct Args {
-    /// Path to configuration file
+    /// Path to configuration file or directory, repeat to merge several in order
     #[arg(short, long, default_value = "config.yaml")]
-    config: PathBuf,
+    config: Vec<PathBuf>,
+
+    /// Configure from environment variables only, without reading a config file
+    #[arg(long, conflicts_with = "config")]
+    no_config_file: bool,
 
     /// Run once and exit (for testing)
     #[arg(long)]
     once: bool,
+
+    /// Start right away instead of waiting for backup.initial_delay
+    #[arg(long)]
+    no_initial_delay: bool,
+
+    /// Only log what would be uploaded and deleted, without changing storage
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Validate the configuration and exit, without connecting to Redis or storage
+    #[arg(long)]
+    config_check: bool,
+
+    /// Only log errors, overrides logging.level
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log debug messages, or trace messages if given twice; overrides logging.level
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+impl Args {
+    /// Log level requested on the command line, if any
+    fn log_level(&self) -> Option<&'static str> {
+        match (self.quiet, self.verbose) {
+            (true, _) => Some("error"),
+            (false, 0) => None,
+            (false, 1) => Some("debug"),
+            (false, _) => Some("trace"),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Verify a stored backup against its SHA-256 checksum
+    Verify {
+        /// Object key of the backup, defaults to the newest backup of this node
+        #[arg(long)]
+        key: Option<String>,
+    },
+
+    /// List the stored backups of this node, newest first
+    List {
+        /// Print one JSON object per backup instead of a table
+        #[arg(long)]
+        json: bool,
+
+        /// List the backups of all nodes under the storage prefix
+        #[arg(long)]
+        all_nodes: bool,
+
+        /// Only list backups taken at or after this time, RFC3339 or e.g. "7d ago"
+        #[arg(long, value_parser = config::parse_timestamp)]
+        since: Option<DateTime<Utc>>,
+
+        /// Only list backups taken at or before this time, RFC3339 or e.g. "1d ago"
+        #[arg(long, value_parser = config::parse_timestamp)]
+        until: Option<DateTime<Utc>>,
+    },
+
+    /// Show the RDB version, auxiliary fields and databases of a stored backup,
+    /// downloading only the start of the file
+    Inspect {
+        /// Object key of the backup, defaults to the newest backup of the node
+        #[arg(long)]
+        key: Option<String>,
+
+        /// Node whose newest backup is inspected, defaults to redis.node_name
+        #[arg(long)]
+        node: Option<String>,
+
+        /// Number of bytes to download from the start of the backup
+        #[arg(long, default_value = "1MiB", value_parser = config::parse_byte_size)]
+        bytes: u64,
+    },
+
+    /// Restore a stored backup to a local file
+    Restore {
+        /// Object key of the backup, defaults to the newest backup of this node
+        #[arg(long)]
+        key: Option<String>,
+
+        /// File to write, defaults to dump_path or data_path/dump_filename
+        #[arg(long)]
+        target_file: Option<PathBuf>,
+
+        /// Owner of the restored file, e.g. "redis:redis" or "999:999"
+        #[arg(long, value_parser = restore::parse_owner)]
+        owner: Option<restore::Owner>,
+
+        /// Permission bits of the restored file in octal, e.g. "0640"
+        #[arg(long, value_parser = restore::parse_mode)]
+        mode: Option<u32>,
+
+        /// Only restore the keys of this database, e.g. "3"
+        #[arg(long)]
+        db: Option<u64>,
+    },
+
+    /// Print the JSON Schema of the configuration file
+    Schema,
+
+    /// Send a test notification to the configured webhook and wait for its delivery
+    TestNotify {
+        /// Outcome of the synthetic backup event
+        #[arg(long, value_enum, default_value_t = TestEvent::Success)]
+        event: TestEvent,
+    },
+    /// Print the version and the effective configuration after environment
+    /// overrides, with credentials redacted
+    Info {
+        /// Print a JSON object instead of text
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// Outcome of the event sent by test-notify
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum TestEvent {
+    Success,
+    Failure,
+}
+
+/// Send a synthetic backup event through the notifier, failing if it is not delivered
+async fn test_notify(
+    config: &config::Config,
+    metrics: Arc<RwLock<Metrics>>,
+    event: TestEvent,
+) -> Result<()> {
+    let Some(notifications) = &config.notifications else {
+        anyhow::bail!("No notifications configured");
+    };
+    let notifier = notify::Notifier::new(notifications.clone(), metrics)?;
+
+    let (outcome, error) = match event {
+        TestEvent::Success => ("success", None),
+        TestEvent::Failure => (
+            "failure",
+            Some("Test notification, no backup failed".to_string()),
+        ),
+    };
+    let event = notify::BackupEvent {
+        node: config.redis.node_name.clone(),
+        key: None,
+        size: None,
+        duration_seconds: 0.0,
+        outcome,
+        error,
+    };
+
+    notifier
+        .send(&event)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to deliver test notification: {}", e))?;
+    println!("Test notification ({}) delivered", outcome);
+    Ok(())
+}
+
+/// Print the version, storage backends and effective configuration
+fn print_info(config: &config::Config, config_files: &[PathBuf], json: bool) -> Result<()> {
+    let urls = std::iter::once(&config.backup.storage_url)
+        .chain(config.backup.destinations.iter().map(|d| &d.storage_url));
+    // The reasons of invalid destinations are among the validation errors
+    let storage: Vec<_> = config
+        .backup
+        .storage_locations()
+        .into_iter()
+        .zip(urls)
+        .map(|(location, url)| match location {
+            Ok(storage) => (
+                storage.to_string(),
+                storage.storage_type.label().to_string(),
+            ),
+            Err(_) => (url.clone(), "invalid".to_string()),
+        })
+        .collect();
+    let validation = config.validate();
+    let target = format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS);
+
+    if json {
+        let info = serde_json::json!({
+            "version": VERSION,
+            "target": target,
+            "config_files": config_files,
+            "storage": storage
+                .iter()
+                .map(|(url, backend)| serde_json::json!({"url": url, "backend": backend}))
+                .collect::<Vec<_>>(),
+            "validation": validation,
+            "config": config::redacted(config),
+        });
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+
+    println!("redis-vault {} ({})", VERSION, target);
+    match config_files {
+        [] => println!("Config file: none, environment only"),
+        [path] => println!("Config file: {}", path.display()),
+        paths => println!(
+            "Config files: {}",
+            paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+    for (url, backend) in &storage {
+        println!("Storage: {} ({})", url, backend);
+    }
+    for warning in &validation.warnings {
+        println!("Warning: {}", warning);
+    }
+    for error in &validation.errors {
+        println!("Error: {}", error);
+    }
+    println!("{:#?}", config);
+    Ok(())
 }
 
 fn spawn_metrics_server(
+    listener: metrics::MetricsListener,
     metrics: Arc<RwLock<metrics::Metrics>>,
-    port: u16,
-    listen_address: String,
+    config: config::MetricsConfig,
+    shutdown: watch::Receiver<bool>,
 ) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
-        if let Err(e) = metrics::start_metrics_server(metrics, port, listen_address).await {
+        if let Err(e) =
+            metrics::start_metrics_server(listener, metrics.clone(), config, shutdown).await
+        {
             error!("Metrics server failed: {}", e);
+            metrics::write_metrics(&metrics).await.metrics_server_error = Some(e.to_string());
         }
     })
 }
@@ -58,39 +302,176 @@ async fn main() -> Result<()> {
     // Parse arguments
     let args = Args::parse();
 
+    // The schema describes the configuration file, so none needs to be loaded
+    if let Some(Command::Schema) = args.command {
+        println!("{}", config::json_schema()?);
+        return Ok(());
+    }
+
     // Load configuration
-    let config = load_config(&args.config)?;
+    let config_files = match args.no_config_file {
+        true => Vec::new(),
+        false => args.config.clone(),
+    };
+    let mut config = load_config(&config_files)?;
+
+    // Command line verbosity overrides logging.level, RUST_LOG still takes precedence
+    if let Some(level) = args.log_level() {
+        config.logging.level = level.to_string();
+    }
+
+    // Exits non-zero through the error if the configuration is invalid
+    if args.config_check {
+        let validation = config.validate();
+        for warning in &validation.warnings {
+            println!("Warning: {}", warning);
+        }
+        for error in &validation.errors {
+            println!("Error: {}", error);
+        }
+        if !validation.errors.is_empty() {
+            anyhow::bail!(
+                "Invalid configuration: {} error(s)",
+                validation.errors.len()
+            );
+        }
+        println!("OK");
+        return Ok(());
+    }
+
+    // Reports the configuration as loaded, so it works even if validation fails
+    if let Some(Command::Info { json }) = args.command {
+        return print_info(&config, &config_files, json);
+    }
+
+    // Inspecting another node's backups resolves keys for that node
+    if let Some(Command::Inspect {
+        node: Some(node), ..
+    }) = &args.command
+    {
+        config.redis.node_name = node.clone();
+    }
+
+    // The delay lets replication settle after a restart, one-off runs in CI can skip it
+    if args.no_initial_delay {
+        config.backup.initial_delay = "0s".to_string();
+    }
 
-    // Initialize logging using custom config
-    init_logging(&config.logging.level, &config.logging.format);
+    // Initialize logging using custom config, keeping stdout free for listings
+    let log_to_stderr = matches!(args.command, Some(Command::List { .. }));
+    init_logging(
+        &config.logging.level,
+        &config.logging.format,
+        log_to_stderr,
+        &config.logging.redact_patterns,
+    )?;
+
+    // The Redis client looks up the TLS provider of the process for redis.tls.insecure_skip_verify
+    if tokio_rustls::rustls::crypto::aws_lc_rs::default_provider()
+        .install_default()
+        .is_err()
+    {
+        debug!("A TLS crypto provider is already installed for the process");
+    }
+
+    // Validate configuration before touching Redis or storage
+    let validation = config.validate();
+    for warning in &validation.warnings {
+        warn!("Configuration: {}", warning);
+    }
+    if !validation.errors.is_empty() {
+        anyhow::bail!("Invalid configuration: {}", validation.errors.join("; "));
+    }
 
     debug!("Config: {:?}", config);
 
     // Initialize metrics
-    let metrics = Arc::new(RwLock::new(Metrics::new()?));
+    let metrics = Arc::new(RwLock::new(Metrics::new(&config.metrics)?));
 
-    // Start metrics server if enabled
-    let metrics_handle = if config.metrics.enabled {
+    // Only needs the webhook, not Redis or storage
+    if let Some(Command::TestNotify { event }) = args.command {
+        return test_notify(&config, metrics, event).await;
+    }
+
+    // Shut down gracefully on SIGTERM/SIGINT
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    shutdown::spawn_signal_listener(shutdown_tx.clone())?;
+
+    // Start metrics server if enabled, one-off commands exit before they could be scraped
+    let metrics_handle = if !config.metrics.enabled {
+        info!("Metrics server disabled");
+        None
+    } else if args.command.is_some() {
+        None
+    } else {
         debug!("Metrics initialized");
+        let listener = metrics::bind_metrics_server(&config.metrics).await?;
         Some(spawn_metrics_server(
+            listener,
             metrics.clone(),
-            config.metrics.port,
-            config.metrics.listen_address.clone(),
+            config.metrics.clone(),
+            shutdown_rx.clone(),
         ))
-    } else {
-        info!("Metrics server disabled");
-        None
     };
 
+    // Keep what is needed to push metrics once the manager owns the configuration
+    let pushgateway = config
+        .metrics
+        .pushgateway_url
+        .clone()
+        .map(|url| (url, config.redis.node_name.clone()));
+
     // Create and run backup manager
-    let mut manager = BackupManager::new(config, metrics).await?;
+    let mut manager = BackupManager::new(config, metrics.clone())
+        .await?
+        .with_dry_run(args.dry_run)
+        .with_config_files(config_files);
 
-    // Run backup manager
-    let backup_result = manager.run(args.once).await;
+    // Run backup manager, or the requested one-off command
+    let backup_result = match args.command {
+        Some(Command::Verify { key }) => manager.verify(key.as_deref()).await,
+        Some(Command::List {
+            json,
+            all_nodes,
+            since,
+            until,
+        }) => manager.list(all_nodes, json, since, until).await,
+        Some(Command::Inspect { key, bytes, .. }) => manager.inspect(key.as_deref(), bytes).await,
+        Some(Command::Restore {
+            key,
+            target_file,
+            owner,
+            mode,
+            db,
+        }) => {
+            let options = restore::RestoreOptions {
+                key,
+                target_file,
+                owner,
+                mode,
+                db,
+            };
+            manager.restore(&options).await
+        }
+        // Handled before the manager was created
+        Some(Command::Schema | Command::Info { .. } | Command::TestNotify { .. }) => Ok(()),
+        None => manager.run(args.once, shutdown_rx).await,
+    };
+
+    // Short-lived runs are not scraped, so hand their metrics to the Pushgateway
+    if let Some((url, instance)) = &pushgateway {
+        match metrics::push_metrics(&metrics, url, instance).await {
+            Ok(()) => debug!("Pushed metrics to Pushgateway"),
+            Err(e) => warn!("Failed to push metrics to Pushgateway: {}", e),
+        }
+    }
 
-    // If we started a metrics server, we should shut it down gracefully
-    if let Some(handle) = metrics_handle {
-        handle.abort();
+    // If we started a metrics server, let in-flight scrapes complete before exiting
+    shutdown_tx.send_replace(true);
+    if let Some(handle) = metrics_handle
+        && let Err(e) = handle.await
+    {
+        error!("Metrics server task failed: {}", e);
     }
 
     backup_result
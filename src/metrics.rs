@@ -1,15 +1,50 @@
 use anyhow::Result;
+use base64::prelude::{BASE64_STANDARD, Engine};
+use chrono::Utc;
+use flate2::Compression;
+use flate2::write::GzEncoder;
 use http_body_util::Full;
 use hyper::body::Bytes;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
-use prometheus::{Encoder, Gauge, Histogram, HistogramOpts, IntCounter, Registry, TextEncoder};
+use prometheus::{
+    Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, Registry, TextEncoder, exponential_buckets,
+};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Write;
 use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard, watch};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+
+use crate::config::{MetricsAuthConfig, MetricsConfig, MetricsTlsConfig};
+use crate::shutdown::shutdown_requested;
+
+/// Waits for the metrics lock longer than this are logged
+const LOCK_WAIT_LOG_THRESHOLD: Duration = Duration::from_millis(10);
+
+/// Timeout for pushing metrics to a Pushgateway
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Job name in the Pushgateway grouping key
+const PUSH_JOB: &str = "redis-vault";
+
+/// Pause after a failed accept, e.g. while out of file descriptors
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
 
 #[derive(Clone)]
 pub struct Metrics {
@@ -24,72 +59,406 @@ pub struct Metrics {
     pub backup_size_bytes: Histogram,
     pub backup_duration_seconds: Histogram,
     pub last_backup_timestamp: Gauge,
+    pub next_backup_timestamp: Gauge,
+    pub circuit_open: IntGauge,
 
     // Storage operations
     pub storage_uploads_total: IntCounter,
     pub storage_deletes_total: IntCounter,
+    pub destination_uploads_total: IntCounterVec,
+    pub upload_verification_failures_total: IntCounterVec,
+    pub storage_operation_duration_seconds: HistogramVec,
+    pub storage_operation_errors_total: IntCounterVec,
+    pub list_pages_total: IntCounterVec,
+
+    // Adaptive throttling, by destination
+    pub storage_throttled_requests_total: IntCounterVec,
+    pub storage_throttle_concurrency: IntGaugeVec,
+    pub storage_throttle_delay_seconds: GaugeVec,
+
+    // Client connections
+    pub client_initializations_total: IntCounterVec,
 
     // Cleanup operations
     pub cleanup_operations_total: IntCounter,
-    pub backups_deleted_total: IntCounter,
+    pub backups_deleted_total: IntCounterVec,
+
+    // Retention state after the last cleanup, by node and destination
+    pub retention_kept_count: IntGaugeVec,
+    pub retention_deleted_count: IntGaugeVec,
+    pub oldest_backup_age_seconds: GaugeVec,
+    pub oldest_backup_timestamp: GaugeVec,
+    pub retention_pending_delete_count: IntGaugeVec,
+    pub listed_objects: IntGaugeVec,
+
+    // Redis server, by node
+    pub redis_up: IntGauge,
+    pub redis_connection_errors_total: IntCounter,
+    pub dataset_bytes: IntGaugeVec,
+    pub redis_version_info: IntGaugeVec,
+
+    // Content of the last backup, by node
+    pub backup_key_count: IntGaugeVec,
+    pub backup_expires_count: IntGaugeVec,
+    pub backup_type_key_count: IntGaugeVec,
+    pub backup_rdb_version_info: IntGaugeVec,
+    /// RDB version last recorded per node, to drop its label after an upgrade
+    rdb_versions: HashMap<String, String>,
+
+    // Integrity checks
+    pub backup_checksum_mismatches_total: IntCounter,
+
+    // Upload budget
+    pub backups_skipped_budget_total: IntCounter,
+    pub backups_skipped_unchanged_total: IntCounter,
+    pub backups_skipped_window_total: IntCounter,
+    pub dump_missing_total: IntCounter,
+    pub upload_budget_remaining_bytes: Gauge,
+
+    // Notifications
+    pub notifications_sent_total: IntCounterVec,
+
+    // Diagnostics
+    pub metrics_lock_wait_seconds: Histogram,
+    pub compression_peak_memory_bytes: IntGaugeVec,
+
+    // Backup status reported on /status
+    started_at: i64,
+    pub last_cycle_success: Option<bool>,
+    pub last_error: Option<String>,
+    /// Error of the metrics server, cleared once it accepts connections again
+    pub metrics_server_error: Option<String>,
+
+    // Readiness reported on /ready, set once storage was reached after the initial delay
+    pub ready: bool,
+}
+
+/// Backup status as reported by the /status endpoint
+#[derive(Debug, Serialize)]
+pub struct BackupStatus {
+    pub healthy: bool,
+    pub last_cycle_success: Option<bool>,
+    pub last_error: Option<String>,
+    pub last_backup_timestamp: Option<i64>,
+    pub seconds_since_last_backup: Option<i64>,
+    pub metrics_server_healthy: bool,
+    pub metrics_server_error: Option<String>,
 }
 
 impl Metrics {
-    pub fn new() -> Result<Self> {
+    pub fn new(config: &MetricsConfig) -> Result<Self> {
         let registry = Arc::new(Registry::new());
 
+        // Every metric is named namespace_subsystem_name, skipping empty parts
+        let subsystem = config.subsystem.clone().unwrap_or_default();
+        let opts = |name: &str, help: &str| {
+            Opts::new(name, help)
+                .namespace(config.namespace.clone())
+                .subsystem(subsystem.clone())
+        };
+
         // Backup operation counters
-        let backups_total = IntCounter::new(
-            "redis_vault_backups_total",
+        let backups_total = IntCounter::with_opts(opts(
+            "backups_total",
             "Total number of backup operations attempted",
-        )?;
+        ))?;
 
-        let backups_successful = IntCounter::new(
-            "redis_vault_backups_successful_total",
+        let backups_successful = IntCounter::with_opts(opts(
+            "backups_successful_total",
             "Total number of successful backup operations",
-        )?;
+        ))?;
 
-        let backups_failed = IntCounter::new(
-            "redis_vault_backups_failed_total",
+        let backups_failed = IntCounter::with_opts(opts(
+            "backups_failed_total",
             "Total number of failed backup operations",
-        )?;
+        ))?;
 
         // Backup operation details
-        let backup_size_bytes = Histogram::with_opts(HistogramOpts::new(
-            "redis_vault_backup_size_bytes",
-            "Size of backup files in bytes",
+        let backup_size_bytes = Histogram::with_opts(
+            HistogramOpts::from(opts("backup_size_bytes", "Size of backup files in bytes"))
+                .buckets(config.size_buckets()?),
+        )?;
+
+        let backup_duration_seconds = Histogram::with_opts(
+            HistogramOpts::from(opts(
+                "backup_duration_seconds",
+                "Duration of backup operations in seconds",
+            ))
+            .buckets(config.duration_buckets()?),
+        )?;
+
+        let last_backup_timestamp = Gauge::with_opts(opts(
+            "last_backup_timestamp_seconds",
+            "Unix timestamp of the last successful backup",
         ))?;
 
-        let backup_duration_seconds = Histogram::with_opts(HistogramOpts::new(
-            "redis_vault_backup_duration_seconds",
-            "Duration of backup operations in seconds",
+        let next_backup_timestamp = Gauge::with_opts(opts(
+            "next_backup_timestamp_seconds",
+            "Unix timestamp of the next scheduled backup",
         ))?;
 
-        let last_backup_timestamp = Gauge::new(
-            "redis_vault_last_backup_timestamp_seconds",
-            "Unix timestamp of the last successful backup",
-        )?;
+        let circuit_open = IntGauge::with_opts(opts(
+            "circuit_open",
+            "Whether backups are paused after repeated failures until storage is reachable",
+        ))?;
 
         // Storage operations
-        let storage_uploads_total = IntCounter::new(
-            "redis_vault_storage_uploads_total",
+        let storage_uploads_total = IntCounter::with_opts(opts(
+            "storage_uploads_total",
             "Total number of storage upload operations by storage type",
-        )?;
+        ))?;
 
-        let storage_deletes_total = IntCounter::new(
-            "redis_vault_storage_deletes_total",
+        let storage_deletes_total = IntCounter::with_opts(opts(
+            "storage_deletes_total",
             "Total number of storage delete operations by storage type",
+        ))?;
+
+        let destination_uploads_total = IntCounterVec::new(
+            opts(
+                "destination_uploads_total",
+                "Total number of backup uploads by destination and outcome",
+            ),
+            &["destination", "outcome"],
+        )?;
+
+        let upload_verification_failures_total = IntCounterVec::new(
+            opts(
+                "upload_verification_failures_total",
+                "Total number of uploaded backups missing from storage or with an unexpected size",
+            ),
+            &["destination"],
+        )?;
+
+        let storage_operation_duration_seconds = HistogramVec::new(
+            HistogramOpts::from(opts(
+                "storage_operation_duration_seconds",
+                "Duration of storage operations in seconds by backend and operation",
+            )),
+            &["backend", "operation"],
+        )?;
+
+        let storage_operation_errors_total = IntCounterVec::new(
+            opts(
+                "storage_operation_errors_total",
+                "Total number of failed storage operations by backend and operation",
+            ),
+            &["backend", "operation"],
+        )?;
+
+        let list_pages_total = IntCounterVec::new(
+            opts(
+                "list_pages_total",
+                "Total number of object listing pages requested from S3 by destination",
+            ),
+            &["destination"],
+        )?;
+
+        // Adaptive throttling
+        let storage_throttled_requests_total = IntCounterVec::new(
+            opts(
+                "storage_throttled_requests_total",
+                "Total number of storage requests rejected with a throttling error by destination",
+            ),
+            &["destination"],
+        )?;
+
+        let storage_throttle_concurrency = IntGaugeVec::new(
+            opts(
+                "storage_throttle_concurrency",
+                "Concurrent storage requests currently allowed by destination",
+            ),
+            &["destination"],
+        )?;
+
+        let storage_throttle_delay_seconds = GaugeVec::new(
+            opts(
+                "storage_throttle_delay_seconds",
+                "Current minimum delay between storage request starts in seconds by destination",
+            ),
+            &["destination"],
+        )?;
+
+        // Client connections
+        let client_initializations_total = IntCounterVec::new(
+            opts(
+                "client_initializations_total",
+                "Total number of Redis and storage client (re-)initializations by client",
+            ),
+            &["client"],
         )?;
 
         // Cleanup operations
-        let cleanup_operations_total = IntCounter::new(
-            "redis_vault_cleanup_operations_total",
+        let cleanup_operations_total = IntCounter::with_opts(opts(
+            "cleanup_operations_total",
             "Total number of cleanup operations performed",
+        ))?;
+
+        let backups_deleted_total = IntCounterVec::new(
+            opts(
+                "backups_deleted_total",
+                "Total number of old backups deleted during cleanup by retention rule releasing them",
+            ),
+            &["reason"],
+        )?;
+
+        // Retention state after the last cleanup
+        let retention_kept_count = IntGaugeVec::new(
+            opts(
+                "retention_kept_count",
+                "Number of backups kept by the last cleanup",
+            ),
+            &["node", "destination"],
+        )?;
+
+        let retention_deleted_count = IntGaugeVec::new(
+            opts(
+                "retention_deleted_count",
+                "Number of backups deleted by the last cleanup",
+            ),
+            &["node", "destination"],
+        )?;
+
+        let oldest_backup_age_seconds = GaugeVec::new(
+            opts(
+                "oldest_backup_age_seconds",
+                "Age of the oldest backup remaining after the last cleanup in seconds",
+            ),
+            &["node", "destination"],
+        )?;
+
+        let oldest_backup_timestamp = GaugeVec::new(
+            opts(
+                "oldest_backup_timestamp_seconds",
+                "Timestamp of the oldest backup remaining after the last cleanup",
+            ),
+            &["node", "destination"],
+        )?;
+
+        let retention_pending_delete_count = IntGaugeVec::new(
+            opts(
+                "retention_pending_delete_count",
+                "Number of backups marked for deletion and waiting for the grace period",
+            ),
+            &["node", "destination"],
         )?;
 
-        let backups_deleted_total = IntCounter::new(
-            "redis_vault_backups_deleted_total",
-            "Total number of old backups deleted during cleanup",
+        let listed_objects = IntGaugeVec::new(
+            opts(
+                "listed_objects",
+                "Number of backups and sidecars listed by the last cleanup",
+            ),
+            &["node", "destination"],
+        )?;
+
+        // Redis server
+        let redis_up = IntGauge::with_opts(opts(
+            "redis_up",
+            "Whether the last Redis role check succeeded",
+        ))?;
+
+        let redis_connection_errors_total = IntCounter::with_opts(opts(
+            "redis_connection_errors_total",
+            "Total number of failed Redis role check attempts",
+        ))?;
+
+        let dataset_bytes = IntGaugeVec::new(
+            opts(
+                "dataset_bytes",
+                "Memory used by Redis as reported by INFO used_memory",
+            ),
+            &["node"],
+        )?;
+
+        let redis_version_info = IntGaugeVec::new(
+            opts("redis_version_info", "Redis server version, always 1"),
+            &["node", "version"],
+        )?;
+
+        // Content of the last backup
+        let backup_key_count = IntGaugeVec::new(
+            opts("backup_key_count", "Number of keys in the last backup"),
+            &["node"],
+        )?;
+
+        let backup_expires_count = IntGaugeVec::new(
+            opts(
+                "backup_expires_count",
+                "Number of keys with an expiry in the last backup",
+            ),
+            &["node"],
+        )?;
+
+        let backup_type_key_count = IntGaugeVec::new(
+            opts(
+                "backup_type_key_count",
+                "Number of keys in the last backup by data type",
+            ),
+            &["node", "type"],
+        )?;
+        let backup_rdb_version_info = IntGaugeVec::new(
+            opts(
+                "backup_rdb_version_info",
+                "RDB format version of the last backup, always 1",
+            ),
+            &["node", "version"],
+        )?;
+
+        // Integrity checks
+        let backup_checksum_mismatches_total = IntCounter::with_opts(opts(
+            "backup_checksum_mismatches_total",
+            "Total number of backups failing checksum verification",
+        ))?;
+
+        // Upload budget
+        let backups_skipped_budget_total = IntCounter::with_opts(opts(
+            "backups_skipped_budget_total",
+            "Total number of backups skipped because they would exceed the daily byte budget",
+        ))?;
+
+        let backups_skipped_unchanged_total = IntCounter::with_opts(opts(
+            "backups_skipped_unchanged_total",
+            "Total number of backups skipped because the files did not change since the last upload",
+        ))?;
+
+        let backups_skipped_window_total = IntCounter::with_opts(opts(
+            "backups_skipped_window_total",
+            "Total number of backups skipped because they fell outside the backup windows",
+        ))?;
+
+        let dump_missing_total = IntCounter::with_opts(opts(
+            "dump_missing_total",
+            "Total number of backups finding no dump or AOF file to upload",
+        ))?;
+
+        let upload_budget_remaining_bytes = Gauge::with_opts(opts(
+            "upload_budget_remaining_bytes",
+            "Bytes remaining in today's upload budget",
+        ))?;
+
+        // Notifications
+        let notifications_sent_total = IntCounterVec::new(
+            opts(
+                "notifications_sent_total",
+                "Total number of webhook notifications by outcome",
+            ),
+            &["outcome"],
+        )?;
+
+        // Diagnostics
+        let metrics_lock_wait_seconds = Histogram::with_opts(
+            HistogramOpts::from(opts(
+                "metrics_lock_wait_seconds",
+                "Time spent waiting to acquire the metrics lock in seconds",
+            ))
+            .buckets(exponential_buckets(0.000001, 10.0, 7)?),
+        )?;
+
+        let compression_peak_memory_bytes = IntGaugeVec::new(
+            opts(
+                "compression_peak_memory_bytes",
+                "Most memory held while compressing the last backup, by node",
+            ),
+            &["node"],
         )?;
 
         // Register all metrics
@@ -99,10 +468,44 @@ impl Metrics {
         registry.register(Box::new(backup_size_bytes.clone()))?;
         registry.register(Box::new(backup_duration_seconds.clone()))?;
         registry.register(Box::new(last_backup_timestamp.clone()))?;
+        registry.register(Box::new(next_backup_timestamp.clone()))?;
+        registry.register(Box::new(circuit_open.clone()))?;
         registry.register(Box::new(storage_uploads_total.clone()))?;
         registry.register(Box::new(storage_deletes_total.clone()))?;
+        registry.register(Box::new(destination_uploads_total.clone()))?;
+        registry.register(Box::new(upload_verification_failures_total.clone()))?;
+        registry.register(Box::new(storage_operation_duration_seconds.clone()))?;
+        registry.register(Box::new(storage_operation_errors_total.clone()))?;
+        registry.register(Box::new(list_pages_total.clone()))?;
+        registry.register(Box::new(storage_throttled_requests_total.clone()))?;
+        registry.register(Box::new(storage_throttle_concurrency.clone()))?;
+        registry.register(Box::new(storage_throttle_delay_seconds.clone()))?;
+        registry.register(Box::new(client_initializations_total.clone()))?;
         registry.register(Box::new(cleanup_operations_total.clone()))?;
         registry.register(Box::new(backups_deleted_total.clone()))?;
+        registry.register(Box::new(retention_kept_count.clone()))?;
+        registry.register(Box::new(retention_deleted_count.clone()))?;
+        registry.register(Box::new(oldest_backup_age_seconds.clone()))?;
+        registry.register(Box::new(oldest_backup_timestamp.clone()))?;
+        registry.register(Box::new(retention_pending_delete_count.clone()))?;
+        registry.register(Box::new(listed_objects.clone()))?;
+        registry.register(Box::new(redis_up.clone()))?;
+        registry.register(Box::new(redis_connection_errors_total.clone()))?;
+        registry.register(Box::new(dataset_bytes.clone()))?;
+        registry.register(Box::new(redis_version_info.clone()))?;
+        registry.register(Box::new(backup_key_count.clone()))?;
+        registry.register(Box::new(backup_expires_count.clone()))?;
+        registry.register(Box::new(backup_type_key_count.clone()))?;
+        registry.register(Box::new(backup_rdb_version_info.clone()))?;
+        registry.register(Box::new(backup_checksum_mismatches_total.clone()))?;
+        registry.register(Box::new(backups_skipped_budget_total.clone()))?;
+        registry.register(Box::new(backups_skipped_unchanged_total.clone()))?;
+        registry.register(Box::new(backups_skipped_window_total.clone()))?;
+        registry.register(Box::new(dump_missing_total.clone()))?;
+        registry.register(Box::new(upload_budget_remaining_bytes.clone()))?;
+        registry.register(Box::new(notifications_sent_total.clone()))?;
+        registry.register(Box::new(metrics_lock_wait_seconds.clone()))?;
+        registry.register(Box::new(compression_peak_memory_bytes.clone()))?;
 
         Ok(Metrics {
             registry,
@@ -112,13 +515,98 @@ impl Metrics {
             backup_size_bytes,
             backup_duration_seconds,
             last_backup_timestamp,
+            next_backup_timestamp,
+            circuit_open,
             storage_uploads_total,
             storage_deletes_total,
+            destination_uploads_total,
+            upload_verification_failures_total,
+            storage_operation_duration_seconds,
+            storage_operation_errors_total,
+            list_pages_total,
+            storage_throttled_requests_total,
+            storage_throttle_concurrency,
+            storage_throttle_delay_seconds,
+            client_initializations_total,
             cleanup_operations_total,
             backups_deleted_total,
+            retention_kept_count,
+            retention_deleted_count,
+            oldest_backup_age_seconds,
+            oldest_backup_timestamp,
+            retention_pending_delete_count,
+            listed_objects,
+            redis_up,
+            redis_connection_errors_total,
+            dataset_bytes,
+            redis_version_info,
+            backup_key_count,
+            backup_expires_count,
+            backup_type_key_count,
+            backup_rdb_version_info,
+            rdb_versions: HashMap::new(),
+            backup_checksum_mismatches_total,
+            backups_skipped_budget_total,
+            backups_skipped_unchanged_total,
+            backups_skipped_window_total,
+            dump_missing_total,
+            upload_budget_remaining_bytes,
+            notifications_sent_total,
+            metrics_lock_wait_seconds,
+            compression_peak_memory_bytes,
+            started_at: Utc::now().timestamp(),
+            last_cycle_success: None,
+            last_error: None,
+            metrics_server_error: None,
+            ready: false,
         })
     }
 
+    /// Record the RDB version of the last backup of `node`
+    pub fn record_rdb_version(&mut self, node: &str, version: u32) {
+        let version = version.to_string();
+        if let Some(previous) = self.rdb_versions.insert(node.to_string(), version.clone())
+            && previous != version
+        {
+            let _ = self
+                .backup_rdb_version_info
+                .remove_label_values(&[node, &previous]);
+        }
+        self.backup_rdb_version_info
+            .with_label_values(&[node, &version])
+            .set(1);
+    }
+
+    /// Record the outcome of a backup cycle, with the error message if it failed
+    pub fn record_cycle(&mut self, error: Option<String>) {
+        self.last_cycle_success = Some(error.is_none());
+        self.last_error = error;
+    }
+
+    /// Current backup status
+    ///
+    /// With `max_backup_age` set, the status is unhealthy once the last successful
+    /// backup is older than that. Until the first backup succeeds, the age is
+    /// counted from process start.
+    pub fn status(&self, max_backup_age: Option<Duration>) -> BackupStatus {
+        let now = Utc::now().timestamp();
+        let last_backup = self.last_backup_timestamp.get() as i64;
+        let last_backup_timestamp = (last_backup > 0).then_some(last_backup);
+
+        let age = now - last_backup_timestamp.unwrap_or(self.started_at);
+        let healthy = max_backup_age.is_none_or(|max| age <= max.as_secs() as i64);
+
+        BackupStatus {
+            healthy,
+            last_cycle_success: self.last_cycle_success,
+            last_error: self.last_error.clone(),
+            last_backup_timestamp,
+            seconds_since_last_backup: last_backup_timestamp.map(|ts| now - ts),
+            metrics_server_healthy: self.metrics_server_error.is_none(),
+            metrics_server_error: self.metrics_server_error.clone(),
+        }
+    }
+
     pub fn gather(&self) -> Result<String> {
         let encoder = TextEncoder::new();
         let metric_families = self.registry.gather();
@@ -128,68 +616,499 @@ impl Metrics {
     }
 }
 
-/// Start the metrics HTTP server using Hyper 1.x
-pub async fn start_metrics_server(
-    metrics: Arc<RwLock<Metrics>>,
-    port: u16,
-    listen_address: String,
-) -> Result<()> {
-    let addr = listen_address
+/// Push all metrics to a Prometheus Pushgateway, grouped by job and `instance`
+///
+/// Replaces the metrics previously pushed for the same grouping key.
+pub async fn push_metrics(metrics: &RwLock<Metrics>, url: &str, instance: &str) -> Result<()> {
+    let body = read_metrics(metrics).await.gather()?;
+
+    let mut url = reqwest::Url::parse(url)?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid Pushgateway URL"))?
+        .pop_if_empty()
+        .extend(["metrics", "job", PUSH_JOB, "instance", instance]);
+
+    reqwest::Client::builder()
+        .timeout(PUSH_TIMEOUT)
+        .build()?
+        .put(url)
+        .header(reqwest::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.without_url())?;
+
+    Ok(())
+}
+
+/// Acquire write access to the shared metrics, recording the time spent waiting on the lock
+pub async fn write_metrics(metrics: &RwLock<Metrics>) -> RwLockWriteGuard<'_, Metrics> {
+    let start = Instant::now();
+    let guard = metrics.write().await;
+    record_lock_wait(&guard, start.elapsed());
+    guard
+}
+
+/// Acquire read access to the shared metrics, recording the time spent waiting on the lock
+pub async fn read_metrics(metrics: &RwLock<Metrics>) -> RwLockReadGuard<'_, Metrics> {
+    let start = Instant::now();
+    let guard = metrics.read().await;
+    record_lock_wait(&guard, start.elapsed());
+    guard
+}
+
+fn record_lock_wait(metrics: &Metrics, wait: Duration) {
+    metrics
+        .metrics_lock_wait_seconds
+        .observe(wait.as_secs_f64());
+
+    if wait > LOCK_WAIT_LOG_THRESHOLD {
+        tracing::debug!("Waited {:?} to acquire the metrics lock", wait);
+    }
+}
+
+/// Prefix of a listen address naming a Unix domain socket
+const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// Bound socket of the metrics server, with its TLS acceptor if configured
+pub struct MetricsListener {
+    socket: Socket,
+    tls: Option<MetricsTls>,
+}
+
+enum Socket {
+    Tcp(TcpListener),
+    /// Unix domain socket and the path of its socket file, removed on shutdown
+    Unix(UnixListener, PathBuf),
+}
+
+/// Accepted connection of either listener, with or without TLS
+trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+impl Socket {
+    async fn accept(&self) -> std::io::Result<Box<dyn Connection>> {
+        Ok(match self {
+            Socket::Tcp(listener) => Box::new(listener.accept().await?.0),
+            Socket::Unix(listener, _) => Box::new(listener.accept().await?.0),
+        })
+    }
+}
+
+/// TLS acceptor of the metrics server
+///
+/// The certificate and key are loaded when the server is bound, so invalid files
+/// fail startup. Once either file is modified, e.g. by a certificate renewal, they
+/// are loaded again for the next connection. While they fail to load, the previous
+/// certificate stays in use.
+struct MetricsTls {
+    config: MetricsTlsConfig,
+    acceptor: TlsAcceptor,
+    /// Modification times of the certificate and key the acceptor was built from
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+impl MetricsTls {
+    fn load(config: &MetricsTlsConfig) -> Result<Self> {
+        Ok(MetricsTls {
+            config: config.clone(),
+            acceptor: tls_acceptor(config)?,
+            modified: tls_file_times(config),
+        })
+    }
+
+    /// Acceptor for the next connection, reloaded first if the files changed
+    fn acceptor(&mut self) -> TlsAcceptor {
+        let modified = tls_file_times(&self.config);
+        if modified != self.modified {
+            match tls_acceptor(&self.config) {
+                Ok(acceptor) => {
+                    tracing::info!("Reloaded metrics server certificate");
+                    self.acceptor = acceptor;
+                    self.modified = modified;
+                }
+                Err(e) => tracing::warn!(
+                    "Failed to reload metrics server certificate, keeping the previous one: {:#}",
+                    e
+                ),
+            }
+        }
+        self.acceptor.clone()
+    }
+}
+
+fn tls_file_times(config: &MetricsTlsConfig) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (modified(&config.cert_file), modified(&config.key_file))
+}
+
+/// Build a TLS acceptor from the PEM certificate chain and private key
+fn tls_acceptor(config: &MetricsTlsConfig) -> Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(&config.cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to read certificates from {:?}: {}",
+                config.cert_file,
+                e
+            )
+        })?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {:?}", config.cert_file);
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key_file).map_err(|e| {
+        anyhow::anyhow!(
+            "Failed to read private key from {:?}: {}",
+            config.key_file,
+            e
+        )
+    })?;
+
+    let provider = Arc::new(tokio_rustls::rustls::crypto::aws_lc_rs::default_provider());
+    let mut server_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| anyhow::anyhow!("Invalid metrics server certificate or key: {}", e))?;
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Bind the metrics server to its configured address
+///
+/// Called before the server is spawned, so a port already in use or an invalid
+/// TLS certificate fails startup instead of silently leaving the process without
+/// metrics. A listen address of `unix:/path/to.sock` binds a Unix domain socket
+/// instead, replacing a socket file left behind by a previous run.
+pub async fn bind_metrics_server(config: &MetricsConfig) -> Result<MetricsListener> {
+    let tls = config.tls.as_ref().map(MetricsTls::load).transpose()?;
+
+    if let Some(path) = config.listen_address.strip_prefix(UNIX_SOCKET_PREFIX) {
+        let path = PathBuf::from(path);
+        remove_stale_socket(&path)?;
+        let listener = UnixListener::bind(&path).map_err(|e| {
+            anyhow::anyhow!("Failed to bind metrics server to {}: {}", path.display(), e)
+        })?;
+        tracing::info!("Metrics server bound to {}", config.listen_address);
+        return Ok(MetricsListener {
+            socket: Socket::Unix(listener, path),
+            tls,
+        });
+    }
+
+    let addr = config
+        .listen_address
         .parse::<std::net::IpAddr>()
         .map_err(|e| anyhow::anyhow!("Invalid listen address: {}", e))?;
 
-    let sock_addr = SocketAddr::new(addr, port);
-
-    // Create TCP listener first - this will fail immediately if port is in use
+    let sock_addr = SocketAddr::new(addr, config.port);
     let listener = TcpListener::bind(&sock_addr)
         .await
         .map_err(|e| anyhow::anyhow!("Failed to bind metrics server to {}: {}", sock_addr, e))?;
 
-    let local_addr = listener.local_addr()?;
-    tracing::info!("Metrics server bound to {}", local_addr);
+    tracing::info!("Metrics server bound to {}", listener.local_addr()?);
+    Ok(MetricsListener {
+        socket: Socket::Tcp(listener),
+        tls,
+    })
+}
+
+/// Remove the socket file at `path` if one exists, refusing to remove other files
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(path).map_err(|e| {
+                anyhow::anyhow!("Failed to remove stale socket {}: {}", path.display(), e)
+            })
+        }
+        Ok(_) => anyhow::bail!(
+            "Failed to bind metrics server to {}: not a socket",
+            path.display()
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => anyhow::bail!("Failed to inspect {}: {}", path.display(), e),
+    }
+}
+
+/// Run the metrics HTTP server using Hyper 1.x on a bound listener
+///
+/// Once a shutdown is requested, no new connections are accepted and the server
+/// returns after in-flight requests have completed.
+pub async fn start_metrics_server(
+    mut listener: MetricsListener,
+    metrics: Arc<RwLock<Metrics>>,
+    config: MetricsConfig,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let max_backup_age = config
+        .max_backup_age
+        .as_deref()
+        .map(humantime::parse_duration)
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("Invalid max_backup_age: {}", e))?;
+    let credentials = config.auth.as_ref().map(Credentials::new).transpose()?;
+
+    let mut connections = JoinSet::new();
+    let mut accept_failed = false;
 
     // Accept connections in a loop
     loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
+        let accepted = tokio::select! {
+            accepted = listener.socket.accept() => accepted,
+            _ = shutdown_requested(&mut shutdown) => break,
+        };
+        // A failed accept does not end the server, it is reported on /status until one succeeds
+        let stream = match accepted {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::error!("Failed to accept metrics connection: {}", e);
+                write_metrics(&metrics).await.metrics_server_error = Some(e.to_string());
+                accept_failed = true;
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        if accept_failed {
+            write_metrics(&metrics).await.metrics_server_error = None;
+            accept_failed = false;
+        }
+        let acceptor = listener.tls.as_mut().map(MetricsTls::acceptor);
         let metrics = metrics.clone();
+        let mut shutdown = shutdown.clone();
+
+        // Reap finished connection tasks
+        while connections.try_join_next().is_some() {}
 
         // Spawn a task to handle each connection
-        tokio::spawn(async move {
+        connections.spawn(async move {
+            // The handshake runs in the connection task, so slow clients do not block accepting
+            let stream = match acceptor {
+                Some(acceptor) => {
+                    let handshake = tokio::select! {
+                        handshake = acceptor.accept(stream) => handshake,
+                        _ = shutdown_requested(&mut shutdown) => return,
+                    };
+                    match handshake {
+                        Ok(stream) => Box::new(stream),
+                        Err(e) => {
+                            tracing::debug!("TLS handshake failed: {}", e);
+                            return;
+                        }
+                    }
+                }
+                None => stream,
+            };
+            let io = TokioIo::new(stream);
+
             // Create a service function that handles requests for this connection
             let service = service_fn(move |req| {
                 let metrics = metrics.clone();
-                async move { handle_request(req, metrics).await }
+                async move { handle_request(req, metrics, max_backup_age, credentials).await }
             });
 
             // Serve HTTP/1.1 requests on this connection
-            if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+            let conn = http1::Builder::new().serve_connection(io, service);
+            tokio::pin!(conn);
+
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                _ = shutdown_requested(&mut shutdown) => {
+                    // Finish the in-flight request, then close the connection
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+
+            if let Err(err) = result {
                 tracing::error!("Error serving connection: {:?}", err);
             }
         });
     }
+
+    tracing::info!("Metrics server shutting down");
+    while connections.join_next().await.is_some() {}
+
+    if let Socket::Unix(_, path) = &listener.socket
+        && let Err(e) = std::fs::remove_file(path)
+    {
+        tracing::warn!("Failed to remove socket {}: {}", path.display(), e);
+    }
+
+    Ok(())
+}
+
+/// Credentials the metrics server expects, as SHA-256 digests
+///
+/// Comparing digests keeps the comparison independent of the secret's length.
+#[derive(Clone, Copy)]
+enum Credentials {
+    /// Digest of `username:password`
+    Basic([u8; 32]),
+    /// Digest of the bearer token
+    Bearer([u8; 32]),
+}
+
+impl Credentials {
+    fn new(auth: &MetricsAuthConfig) -> Result<Self> {
+        match (&auth.username, &auth.password, &auth.token) {
+            (Some(username), Some(password), None) => Ok(Credentials::Basic(
+                Sha256::digest(format!("{}:{}", username, password)).into(),
+            )),
+            (None, None, Some(token)) => Ok(Credentials::Bearer(Sha256::digest(token).into())),
+            _ => anyhow::bail!("metrics.auth requires username and password, or token"),
+        }
+    }
+
+    /// Whether the request's Authorization header matches, in constant time
+    fn authorize<B>(&self, req: &Request<B>) -> bool {
+        let Some((scheme, value)) = req
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().split_once(' '))
+        else {
+            return false;
+        };
+        let (expected, given) = match self {
+            Credentials::Basic(expected) if scheme.eq_ignore_ascii_case("basic") => {
+                match BASE64_STANDARD.decode(value.trim()) {
+                    Ok(decoded) => (expected, decoded),
+                    Err(_) => return false,
+                }
+            }
+            Credentials::Bearer(expected) if scheme.eq_ignore_ascii_case("bearer") => {
+                (expected, value.trim().as_bytes().to_vec())
+            }
+            _ => return false,
+        };
+        let given = Sha256::digest(given);
+        expected
+            .iter()
+            .zip(given.iter())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+
+    /// 401 response asking for these credentials
+    fn challenge(&self) -> Response<Full<Bytes>> {
+        let scheme = match self {
+            Credentials::Basic(_) => "Basic",
+            Credentials::Bearer(_) => "Bearer",
+        };
+        Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(
+                hyper::header::WWW_AUTHENTICATE,
+                format!("{} realm=\"redis-vault\"", scheme),
+            )
+            .body(Full::new(Bytes::from("Unauthorized")))
+            .unwrap()
+    }
+}
+
+/// Whether the request's Accept-Encoding allows a gzip response
+///
+/// `gzip` or `*` must be listed without `q=0`, e.g. `gzip, deflate` or `gzip;q=0.5`.
+fn accepts_gzip<B>(req: &Request<B>) -> bool {
+    req.headers()
+        .get_all(hyper::header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let disabled = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !disabled
+        })
+}
+
+/// Compress a metrics body with gzip
+fn gzip(body: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    encoder.finish()
 }
 
 /// Handle incoming HTTP requests for metrics and health endpoints
-async fn handle_request(
-    req: Request<hyper::body::Incoming>,
+async fn handle_request<B>(
+    req: Request<B>,
     metrics: Arc<RwLock<Metrics>>,
+    max_backup_age: Option<Duration>,
+    credentials: Option<Credentials>,
 ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    // Metrics and status require credentials if configured, probes never do
+    if let Some(credentials) = credentials
+        && matches!(req.uri().path(), "/metrics" | "/status")
+        && !credentials.authorize(&req)
+    {
+        return Ok(credentials.challenge());
+    }
+
     match (req.method(), req.uri().path()) {
         // GET /metrics - Prometheus metrics endpoint
+        // Compressed with gzip if the scraper accepts it, plain text otherwise
         (&Method::GET, "/metrics") => {
-            let metrics = metrics.read().await;
+            let gzip_accepted = accepts_gzip(&req);
+            let metrics = read_metrics(&metrics).await;
             match metrics.gather() {
+                Ok(body) => {
+                    let response = Response::builder()
+                        .status(StatusCode::OK)
+                        .header("content-type", "text/plain; charset=utf-8")
+                        .header("vary", "accept-encoding");
+                    let compressed = if gzip_accepted {
+                        gzip(body.as_bytes())
+                            .map_err(|e| tracing::warn!("Failed to compress metrics: {}", e))
+                            .ok()
+                    } else {
+                        None
+                    };
+                    Ok(match compressed {
+                        Some(compressed) => response
+                            .header("content-encoding", "gzip")
+                            .body(Full::new(Bytes::from(compressed)))
+                            .unwrap(),
+                        None => response.body(Full::new(Bytes::from(body))).unwrap(),
+                    })
+                }
+                Err(e) => {
+                    tracing::error!("Failed to gather metrics: {}", e);
+                    Ok(Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Full::new(Bytes::from("Failed to gather metrics")))
+                        .unwrap())
+                }
+            }
+        }
+
+        // GET /status - Backup status endpoint
+        (&Method::GET, "/status") => {
+            let status = read_metrics(&metrics).await.status(max_backup_age);
+            let code = if status.healthy {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            match serde_json::to_string(&status) {
                 Ok(body) => Ok(Response::builder()
-                    .status(StatusCode::OK)
-                    .header("content-type", "text/plain; charset=utf-8")
+                    .status(code)
+                    .header("content-type", "application/json")
                     .body(Full::new(Bytes::from(body)))
                     .unwrap()),
                 Err(e) => {
-                    tracing::error!("Failed to gather metrics: {}", e);
+                    tracing::error!("Failed to serialize status: {}", e);
                     Ok(Response::builder()
                         .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(Full::new(Bytes::from("Failed to gather metrics")))
+                        .body(Full::new(Bytes::from("Failed to serialize status")))
                         .unwrap())
                 }
             }
@@ -201,6 +1120,19 @@ async fn handle_request(
             .body(Full::new(Bytes::from("OK")))
             .unwrap()),
 
+        // GET /ready - Readiness check endpoint
+        (&Method::GET, "/ready") => {
+            let (status, body) = if read_metrics(&metrics).await.ready {
+                (StatusCode::OK, "READY")
+            } else {
+                (StatusCode::SERVICE_UNAVAILABLE, "NOT READY")
+            };
+            Ok(Response::builder()
+                .status(status)
+                .body(Full::new(Bytes::from(body)))
+                .unwrap())
+        }
+
         // 404 Not Found for all other routes
         _ => Ok(Response::builder()
             .status(StatusCode::NOT_FOUND)
@@ -208,3 +1140,235 @@ async fn handle_request(
             .unwrap()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::get_default_config;
+    use http_body_util::BodyExt;
+    use std::io::Read;
+
+    fn basic() -> Credentials {
+        Credentials::new(&MetricsAuthConfig {
+            username: Some("prometheus".to_string()),
+            password: Some("s3cret".to_string()),
+            token: None,
+        })
+        .unwrap()
+    }
+
+    fn bearer() -> Credentials {
+        Credentials::new(&MetricsAuthConfig {
+            username: None,
+            password: None,
+            token: Some("scrape-token".to_string()),
+        })
+        .unwrap()
+    }
+
+    /// GET request of `path` with an optional Authorization header
+    fn request(path: &str, authorization: Option<&str>) -> Request<()> {
+        let mut request = Request::builder().uri(path);
+        if let Some(authorization) = authorization {
+            request = request.header(hyper::header::AUTHORIZATION, authorization);
+        }
+        request.body(()).unwrap()
+    }
+
+    fn basic_header(userinfo: &str) -> String {
+        format!("Basic {}", BASE64_STANDARD.encode(userinfo))
+    }
+
+    #[test]
+    fn basic_credentials() {
+        let credentials = basic();
+        let header = basic_header("prometheus:s3cret");
+        assert!(credentials.authorize(&request("/metrics", Some(&header))));
+        // The scheme is case insensitive
+        let header = header.replacen("Basic", "basic", 1);
+        assert!(credentials.authorize(&request("/metrics", Some(&header))));
+
+        for userinfo in ["prometheus:wrong", "prometheus:s3cret ", "other:s3cret", ""] {
+            let header = basic_header(userinfo);
+            assert!(
+                !credentials.authorize(&request("/metrics", Some(&header))),
+                "{:?} is authorized",
+                userinfo
+            );
+        }
+        assert!(!credentials.authorize(&request("/metrics", Some("Basic !!!"))));
+        assert!(!credentials.authorize(&request("/metrics", Some("Bearer s3cret"))));
+        assert!(!credentials.authorize(&request("/metrics", None)));
+    }
+
+    #[test]
+    fn bearer_credentials() {
+        let credentials = bearer();
+        assert!(credentials.authorize(&request("/metrics", Some("Bearer scrape-token"))));
+        assert!(credentials.authorize(&request("/metrics", Some("bearer  scrape-token "))));
+
+        assert!(!credentials.authorize(&request("/metrics", Some("Bearer wrong-token"))));
+        assert!(!credentials.authorize(&request("/metrics", Some("Bearer scrape-toke"))));
+        assert!(!credentials.authorize(&request("/metrics", Some("Bearer"))));
+        let header = basic_header("prometheus:scrape-token");
+        assert!(!credentials.authorize(&request("/metrics", Some(&header))));
+        assert!(!credentials.authorize(&request("/metrics", None)));
+    }
+
+    #[test]
+    fn credentials_require_one_kind() {
+        let auth = MetricsAuthConfig {
+            username: Some("prometheus".to_string()),
+            password: Some("s3cret".to_string()),
+            token: Some("scrape-token".to_string()),
+        };
+        assert!(Credentials::new(&auth).is_err());
+        let auth = MetricsAuthConfig {
+            username: Some("prometheus".to_string()),
+            password: None,
+            token: None,
+        };
+        assert!(Credentials::new(&auth).is_err());
+    }
+
+    async fn status_of(
+        metrics: &Arc<RwLock<Metrics>>,
+        path: &str,
+        authorization: Option<&str>,
+    ) -> StatusCode {
+        let response = handle_request(
+            request(path, authorization),
+            metrics.clone(),
+            None,
+            Some(basic()),
+        )
+        .await
+        .unwrap();
+        let status = response.status();
+        if status == StatusCode::UNAUTHORIZED {
+            assert_eq!(
+                response.headers()[hyper::header::WWW_AUTHENTICATE],
+                "Basic realm=\"redis-vault\""
+            );
+        }
+        // Drain the body, as a client would
+        response.into_body().collect().await.unwrap();
+        status
+    }
+
+    #[tokio::test]
+    async fn probes_stay_unauthenticated() {
+        let metrics = Arc::new(RwLock::new(
+            Metrics::new(&get_default_config().metrics).unwrap(),
+        ));
+        write_metrics(&metrics).await.ready = true;
+        let header = basic_header("prometheus:s3cret");
+        let wrong = basic_header("prometheus:wrong");
+
+        for path in ["/metrics", "/status"] {
+            assert_eq!(
+                status_of(&metrics, path, None).await,
+                StatusCode::UNAUTHORIZED
+            );
+            assert_eq!(
+                status_of(&metrics, path, Some(&wrong)).await,
+                StatusCode::UNAUTHORIZED
+            );
+            assert_ne!(
+                status_of(&metrics, path, Some(&header)).await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert_eq!(
+            status_of(&metrics, "/metrics", Some(&header)).await,
+            StatusCode::OK
+        );
+        for path in ["/health", "/ready"] {
+            assert_eq!(status_of(&metrics, path, None).await, StatusCode::OK);
+            assert_eq!(
+                status_of(&metrics, path, Some(&wrong)).await,
+                StatusCode::OK
+            );
+        }
+    }
+
+    fn accept_encoding(values: &[&str]) -> Request<()> {
+        let mut request = Request::builder().uri("/metrics");
+        for value in values {
+            request = request.header(hyper::header::ACCEPT_ENCODING, *value);
+        }
+        request.body(()).unwrap()
+    }
+
+    #[test]
+    fn gzip_acceptance() {
+        for accepted in [
+            "gzip",
+            "gzip, deflate, br",
+            "deflate, gzip;q=0.5",
+            "GZip",
+            "br;q=1.0, GZIP ; Q=0.1",
+            "*",
+            "identity, *;q=0.8",
+        ] {
+            assert!(
+                accepts_gzip(&accept_encoding(&[accepted])),
+                "{:?} does not accept gzip",
+                accepted
+            );
+        }
+        for refused in [
+            "gzip;q=0",
+            "gzip; q=0.0",
+            "*;q=0",
+            "deflate, br",
+            "identity",
+            "x-gzip-like",
+            "",
+        ] {
+            assert!(
+                !accepts_gzip(&accept_encoding(&[refused])),
+                "{:?} accepts gzip",
+                refused
+            );
+        }
+        // Without the header the body is sent as it is
+        assert!(!accepts_gzip(&accept_encoding(&[])));
+        // Codings may be split across several headers
+        assert!(accepts_gzip(&accept_encoding(&["deflate", "gzip"])));
+    }
+
+    #[tokio::test]
+    async fn metrics_are_compressed_when_accepted() {
+        let metrics = Arc::new(RwLock::new(
+            Metrics::new(&get_default_config().metrics).unwrap(),
+        ));
+        for (value, compressed) in [
+            (Some("gzip"), true),
+            (Some("gzip;q=0"), false),
+            (None, false),
+        ] {
+            let request = accept_encoding(value.as_slice());
+            let response = handle_request(request, metrics.clone(), None, None)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let encoding = response
+                .headers()
+                .get(hyper::header::CONTENT_ENCODING)
+                .cloned();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            if compressed {
+                assert_eq!(encoding.unwrap(), "gzip");
+                let mut decoded = String::new();
+                flate2::read::GzDecoder::new(&body[..])
+                    .read_to_string(&mut decoded)
+                    .unwrap();
+                assert!(decoded.contains("# TYPE"), "{}", decoded);
+            } else {
+                assert!(encoding.is_none(), "{:?} for {:?}", encoding, value);
+                assert!(String::from_utf8_lossy(&body).contains("# TYPE"));
+            }
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Webhook notifications
+//!
+//! This module posts the outcome of every backup to a webhook, e.g. Slack or
+//! Opsgenie. Notifications are delivered in the background with a timeout, so a
+//! slow or failing webhook never delays or fails a backup.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::task::JoinSet;
+use tracing::{debug, warn};
+
+use crate::backup::BackupError;
+use crate::config::NotificationsConfig;
+use crate::metrics::{Metrics, write_metrics};
+
+/// Outcome of a backup, as sent to the webhook
+#[derive(Debug, Serialize)]
+pub struct BackupEvent {
+    pub node: String,
+    pub key: Option<String>,
+    pub size: Option<u64>,
+    pub duration_seconds: f64,
+    pub outcome: &'static str,
+    pub error: Option<String>,
+}
+
+impl BackupEvent {
+    /// Render the request body, from the template if one is configured
+    fn body(&self, template: Option<&str>) -> Result<String> {
+        let Some(template) = template else {
+            return Ok(serde_json::to_string(self)?);
+        };
+
+        // Values are JSON escaped, so they can be used inside JSON string templates
+        let escape = |value: &str| {
+            let quoted = serde_json::Value::from(value).to_string();
+            quoted[1..quoted.len() - 1].to_string()
+        };
+
+        Ok(template
+            .replace("{{node}}", &escape(&self.node))
+            .replace("{{key}}", &escape(self.key.as_deref().unwrap_or_default()))
+            .replace(
+                "{{size}}",
+                &self.size.map(|s| s.to_string()).unwrap_or_default(),
+            )
+            .replace("{{duration}}", &format!("{:.3}", self.duration_seconds))
+            .replace("{{outcome}}", self.outcome)
+            .replace(
+                "{{error}}",
+                &escape(self.error.as_deref().unwrap_or_default()),
+            ))
+    }
+}
+
+/// Posts backup events to the configured webhook
+pub struct Notifier {
+    client: reqwest::Client,
+    config: NotificationsConfig,
+    metrics: Arc<RwLock<Metrics>>,
+    pending: JoinSet<()>,
+}
+
+impl Notifier {
+    pub fn new(config: NotificationsConfig, metrics: Arc<RwLock<Metrics>>) -> Result<Self> {
+        let timeout = humantime::parse_duration(&config.timeout)
+            .map_err(|e| BackupError::Config(format!("Invalid notifications.timeout: {}", e)))?;
+
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+
+        Ok(Notifier {
+            client,
+            config,
+            metrics,
+            pending: JoinSet::new(),
+        })
+    }
+
+    /// Request posting an event to the webhook
+    fn request(&self, event: &BackupEvent) -> Result<reqwest::RequestBuilder> {
+        let body = event.body(self.config.template.as_deref())?;
+        Ok(self
+            .client
+            .post(&self.config.webhook_url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body))
+    }
+
+    /// Send an event in the background
+    pub fn notify(&mut self, event: BackupEvent) {
+        // Drop the results of notifications delivered in the meantime
+        while self.pending.try_join_next().is_some() {}
+
+        let request = match self.request(&event) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Failed to render notification: {}", e);
+                return;
+            }
+        };
+        let metrics = self.metrics.clone();
+
+        self.pending.spawn(async move {
+            match deliver(request, &metrics).await {
+                Ok(()) => debug!("Notification sent for backup {}", event.outcome),
+                Err(e) => warn!("Failed to send notification: {}", e),
+            }
+        });
+    }
+
+    /// Send an event and wait for the webhook to accept it
+    pub async fn send(&self, event: &BackupEvent) -> Result<()> {
+        let request = self.request(event)?;
+        Ok(deliver(request, &self.metrics).await?)
+    }
+
+    /// Wait for notifications still in flight, bounded by the request timeout
+    pub async fn flush(&mut self) {
+        while self.pending.join_next().await.is_some() {}
+    }
+}
+
+/// Send a notification request, counting its outcome
+async fn deliver(
+    request: reqwest::RequestBuilder,
+    metrics: &RwLock<Metrics>,
+) -> std::result::Result<(), reqwest::Error> {
+    // The URL usually embeds a secret, so it is stripped from errors
+    let result = match request.send().await {
+        Ok(response) => response
+            .error_for_status()
+            .map(|_| ())
+            .map_err(|e| e.without_url()),
+        Err(e) => Err(e.without_url()),
+    };
+
+    let outcome = if result.is_ok() { "success" } else { "failure" };
+    write_metrics(metrics)
+        .await
+        .notifications_sent_total
+        .with_label_values(&[outcome])
+        .inc();
+
+    result
+}
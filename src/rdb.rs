@@ -0,0 +1,845 @@
+//! RDB file parsing
+//!
+//! A minimal parser for the Redis RDB format. Values are not decoded, only their
+//! extent in the file is determined. That is enough to copy the entries of a
+//! single database into a new, smaller RDB file, or to summarize a file from just
+//! its first bytes.
+
+use std::collections::BTreeMap;
+
+/// Magic at the start of every RDB file, followed by a four digit version
+const MAGIC: &[u8] = b"REDIS";
+const HEADER_LEN: usize = 9;
+
+/// First RDB version with a CRC64 checksum after the EOF opcode
+const CHECKSUM_VERSION: u32 = 5;
+
+// Opcodes
+const OPCODE_SLOT_INFO: u8 = 244;
+const OPCODE_FUNCTION2: u8 = 245;
+const OPCODE_FUNCTION_PRE_GA: u8 = 246;
+const OPCODE_MODULE_AUX: u8 = 247;
+const OPCODE_IDLE: u8 = 248;
+const OPCODE_FREQ: u8 = 249;
+const OPCODE_AUX: u8 = 250;
+const OPCODE_RESIZEDB: u8 = 251;
+const OPCODE_EXPIRETIME_MS: u8 = 252;
+const OPCODE_EXPIRETIME: u8 = 253;
+const OPCODE_SELECTDB: u8 = 254;
+const OPCODE_EOF: u8 = 255;
+
+// Special string encodings
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+/// Names of the Redis data types as reported by the TYPE command, with all module
+/// types reported as "module"
+pub const TYPE_NAMES: [&str; 7] = ["string", "list", "set", "zset", "hash", "stream", "module"];
+
+// Module value opcodes
+const MODULE_OPCODE_EOF: u64 = 0;
+const MODULE_OPCODE_SINT: u64 = 1;
+const MODULE_OPCODE_UINT: u64 = 2;
+const MODULE_OPCODE_FLOAT: u64 = 3;
+const MODULE_OPCODE_DOUBLE: u64 = 4;
+const MODULE_OPCODE_STRING: u64 = 5;
+
+/// Errors while parsing an RDB file
+#[derive(Debug, thiserror::Error)]
+pub enum RdbError {
+    #[error("Not an RDB file: missing REDIS magic")]
+    InvalidMagic,
+    #[error("Invalid RDB version {0:?}")]
+    InvalidVersion(String),
+    #[error("Unexpected end of RDB data at offset {0}")]
+    UnexpectedEof(usize),
+    #[error("Unsupported RDB type {0} at offset {1}")]
+    UnsupportedType(u8, usize),
+    #[error("Invalid RDB encoding at offset {0}")]
+    InvalidEncoding(usize),
+}
+
+/// Length or special encoding marker
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+/// Cursor over the raw RDB data
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], RdbError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(RdbError::UnexpectedEof(self.data.len()))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, RdbError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn length(&mut self) -> Result<Length, RdbError> {
+        let first = self.byte()?;
+        Ok(match first >> 6 {
+            0 => Length::Len(u64::from(first & 0x3f)),
+            1 => Length::Len(u64::from(first & 0x3f) << 8 | u64::from(self.byte()?)),
+            2 => match first {
+                0x80 => Length::Len(u64::from(u32::from_be_bytes(self.array()?))),
+                0x81 => Length::Len(u64::from_be_bytes(self.array()?)),
+                _ => return Err(RdbError::InvalidEncoding(self.pos - 1)),
+            },
+            _ => Length::Encoded(first & 0x3f),
+        })
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(self.take(N)?);
+        Ok(bytes)
+    }
+
+    /// A plain length, special encodings are not allowed here
+    fn len(&mut self) -> Result<u64, RdbError> {
+        match self.length()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(RdbError::InvalidEncoding(self.pos - 1)),
+        }
+    }
+
+    /// Skip a length as a byte count, which must fit into memory
+    fn skip_len(&mut self, len: u64) -> Result<(), RdbError> {
+        let len = usize::try_from(len).map_err(|_| RdbError::UnexpectedEof(self.data.len()))?;
+        self.take(len)?;
+        Ok(())
+    }
+
+    /// A string, decoding integer and LZF encodings
+    fn string(&mut self) -> Result<Vec<u8>, RdbError> {
+        Ok(match self.length()? {
+            Length::Len(len) => {
+                let len =
+                    usize::try_from(len).map_err(|_| RdbError::UnexpectedEof(self.data.len()))?;
+                self.take(len)?.to_vec()
+            }
+            Length::Encoded(ENC_INT8) => (self.byte()? as i8).to_string().into_bytes(),
+            Length::Encoded(ENC_INT16) => {
+                i16::from_le_bytes(self.array()?).to_string().into_bytes()
+            }
+            Length::Encoded(ENC_INT32) => {
+                i32::from_le_bytes(self.array()?).to_string().into_bytes()
+            }
+            Length::Encoded(ENC_LZF) => {
+                let compressed = self.len()?;
+                let len = self.len()?;
+                let offset = self.pos;
+                let compressed = usize::try_from(compressed)
+                    .map_err(|_| RdbError::UnexpectedEof(self.data.len()))?;
+                lzf_decompress(self.take(compressed)?, len)
+                    .ok_or(RdbError::InvalidEncoding(offset))?
+            }
+            Length::Encoded(_) => return Err(RdbError::InvalidEncoding(self.pos - 1)),
+        })
+    }
+
+    fn skip_string(&mut self) -> Result<(), RdbError> {
+        match self.length()? {
+            Length::Len(len) => self.skip_len(len),
+            Length::Encoded(ENC_INT8) => self.skip_len(1),
+            Length::Encoded(ENC_INT16) => self.skip_len(2),
+            Length::Encoded(ENC_INT32) => self.skip_len(4),
+            Length::Encoded(ENC_LZF) => {
+                let compressed = self.len()?;
+                self.len()?;
+                self.skip_len(compressed)
+            }
+            Length::Encoded(_) => Err(RdbError::InvalidEncoding(self.pos - 1)),
+        }
+    }
+
+    /// Skip `count` repetitions of an element
+    fn skip_each(
+        &mut self,
+        count: u64,
+        mut skip: impl FnMut(&mut Self) -> Result<(), RdbError>,
+    ) -> Result<(), RdbError> {
+        for _ in 0..count {
+            skip(self)?;
+        }
+        Ok(())
+    }
+
+    /// Skip a double in the string based format of RDB_TYPE_ZSET
+    fn skip_string_double(&mut self) -> Result<(), RdbError> {
+        match self.byte()? {
+            // NaN, +inf and -inf have no payload
+            253..=255 => Ok(()),
+            len => self.skip_len(u64::from(len)),
+        }
+    }
+
+    /// Skip the opcode stream of a module value, up to its EOF opcode
+    fn skip_module_opcodes(&mut self) -> Result<(), RdbError> {
+        loop {
+            match self.len()? {
+                MODULE_OPCODE_EOF => return Ok(()),
+                MODULE_OPCODE_SINT | MODULE_OPCODE_UINT => {
+                    self.len()?;
+                }
+                MODULE_OPCODE_FLOAT => self.skip_len(4)?,
+                MODULE_OPCODE_DOUBLE => self.skip_len(8)?,
+                MODULE_OPCODE_STRING => self.skip_string()?,
+                _ => return Err(RdbError::InvalidEncoding(self.pos - 1)),
+            }
+        }
+    }
+
+    fn skip_stream(&mut self, value_type: u8) -> Result<(), RdbError> {
+        // Listpacks with their master ID
+        let listpacks = self.len()?;
+        self.skip_each(listpacks, |r| {
+            r.skip_string()?;
+            r.skip_string()
+        })?;
+
+        // Length and last ID, followed by first ID, max deleted ID and entries added
+        let header_lengths = if value_type >= 19 { 8 } else { 3 };
+        self.skip_each(header_lengths, |r| r.len().map(|_| ()))?;
+
+        // Consumer groups
+        let groups = self.len()?;
+        self.skip_each(groups, |r| {
+            r.skip_string()?;
+            r.len()?;
+            r.len()?;
+            if value_type >= 19 {
+                r.len()?;
+            }
+
+            // Pending entries: stream ID, delivery time and count
+            let pending = r.len()?;
+            r.skip_each(pending, |r| {
+                r.skip_len(16 + 8)?;
+                r.len().map(|_| ())
+            })?;
+
+            let consumers = r.len()?;
+            r.skip_each(consumers, |r| {
+                r.skip_string()?;
+                r.skip_len(if value_type >= 21 { 16 } else { 8 })?;
+                let pending = r.len()?;
+                r.skip_each(pending, |r| r.skip_len(16))
+            })
+        })
+    }
+
+    fn skip_value(&mut self, value_type: u8) -> Result<(), RdbError> {
+        let offset = self.pos;
+        match value_type {
+            // String, and encodings stored as a single blob (ziplist, intset, listpack)
+            0 | 9..=13 | 16 | 17 | 20 => self.skip_string(),
+            // List and set
+            1 | 2 => {
+                let len = self.len()?;
+                self.skip_each(len, Self::skip_string)
+            }
+            // Sorted set with string encoded scores
+            3 => {
+                let len = self.len()?;
+                self.skip_each(len, |r| {
+                    r.skip_string()?;
+                    r.skip_string_double()
+                })
+            }
+            // Hash
+            4 => {
+                let len = self.len()?;
+                self.skip_each(len.saturating_mul(2), Self::skip_string)
+            }
+            // Sorted set with binary scores
+            5 => {
+                let len = self.len()?;
+                self.skip_each(len, |r| {
+                    r.skip_string()?;
+                    r.skip_len(8)
+                })
+            }
+            // Module value: module ID, then its opcode stream
+            7 => {
+                self.len()?;
+                self.skip_module_opcodes()
+            }
+            // Quicklist of ziplists
+            14 => {
+                let len = self.len()?;
+                self.skip_each(len, Self::skip_string)
+            }
+            // Streams
+            15 | 19 | 21 => self.skip_stream(value_type),
+            // Quicklist of listpacks with container type
+            18 => {
+                let len = self.len()?;
+                self.skip_each(len, |r| {
+                    r.len()?;
+                    r.skip_string()
+                })
+            }
+            // Hash with field expiration: minimum expiry, then TTL, field and value
+            24 => {
+                self.skip_len(8)?;
+                let len = self.len()?;
+                self.skip_each(len, |r| {
+                    r.len()?;
+                    r.skip_string()?;
+                    r.skip_string()
+                })
+            }
+            // Listpack hash with field expiration: minimum expiry, then the listpack
+            25 => {
+                self.skip_len(8)?;
+                self.skip_string()
+            }
+            _ => Err(RdbError::UnsupportedType(value_type, offset)),
+        }
+    }
+}
+
+/// Decompress LZF data of a known decompressed length
+fn lzf_decompress(input: &[u8], len: u64) -> Option<Vec<u8>> {
+    let len = usize::try_from(len).ok()?;
+    // The length is read from the file, so the input size bounds the capacity
+    let mut out = Vec::with_capacity(len.min(input.len().saturating_mul(4)));
+    let mut pos = 0;
+
+    while pos < input.len() {
+        let ctrl = usize::from(input[pos]);
+        pos += 1;
+
+        if ctrl < 32 {
+            // Literal run of ctrl + 1 bytes
+            let literal = input.get(pos..pos + ctrl + 1)?;
+            out.extend_from_slice(literal);
+            pos += ctrl + 1;
+        } else {
+            // Back reference, the length is extended by another byte if all bits are set
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += usize::from(*input.get(pos)?);
+                pos += 1;
+            }
+            let distance = ((ctrl & 0x1f) << 8) + usize::from(*input.get(pos)?) + 1;
+            pos += 1;
+
+            let start = out.len().checked_sub(distance)?;
+            for i in 0..run + 2 {
+                out.push(out[start + i]);
+            }
+        }
+
+        if out.len() > len {
+            return None;
+        }
+    }
+
+    (out.len() == len).then_some(out)
+}
+
+/// Element of an RDB file
+pub enum Item<'a> {
+    /// Auxiliary field, function library or module data, not tied to a database
+    Global(&'a [u8]),
+    /// Start of the entries of a database
+    SelectDb(u64),
+    /// Number of keys and keys with an expiry in the current database
+    ResizeDb { keys: u64, expires: u64 },
+    /// Key and value, the raw bytes include its expiry and eviction hints
+    Entry {
+        db: u64,
+        value_type: u8,
+        expires: bool,
+        raw: &'a [u8],
+    },
+    /// End of the file
+    Eof,
+}
+
+/// Parser yielding the elements of an RDB file one by one
+pub struct Parser<'a> {
+    reader: Reader<'a>,
+    version: u32,
+    db: u64,
+    done: bool,
+}
+
+impl<'a> Parser<'a> {
+    /// Start parsing, validating the header
+    pub fn new(data: &'a [u8]) -> Result<Self, RdbError> {
+        let version = parse_header(data)?;
+        Ok(Parser {
+            reader: Reader {
+                data,
+                pos: HEADER_LEN,
+            },
+            version,
+            db: 0,
+            done: false,
+        })
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Next element, or None after the EOF opcode
+    pub fn next_item(&mut self) -> Result<Option<Item<'a>>, RdbError> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let reader = &mut self.reader;
+        let mut start = reader.pos;
+        let mut expires = false;
+        loop {
+            let opcode = reader.byte()?;
+            match opcode {
+                OPCODE_EOF => {
+                    if self.version >= CHECKSUM_VERSION {
+                        reader.skip_len(8)?;
+                    }
+                    self.done = true;
+                    return Ok(Some(Item::Eof));
+                }
+                // Database of the following entries
+                OPCODE_SELECTDB => {
+                    self.db = reader.len()?;
+                    return Ok(Some(Item::SelectDb(self.db)));
+                }
+                OPCODE_AUX => {
+                    reader.skip_string()?;
+                    reader.skip_string()?;
+                    return Ok(Some(Item::Global(&reader.data[start..reader.pos])));
+                }
+                OPCODE_MODULE_AUX => {
+                    // Module ID, when opcode and when, then the opcode stream
+                    reader.len()?;
+                    reader.len()?;
+                    reader.len()?;
+                    reader.skip_module_opcodes()?;
+                    return Ok(Some(Item::Global(&reader.data[start..reader.pos])));
+                }
+                OPCODE_FUNCTION2 => {
+                    reader.skip_string()?;
+                    return Ok(Some(Item::Global(&reader.data[start..reader.pos])));
+                }
+                OPCODE_FUNCTION_PRE_GA => {
+                    return Err(RdbError::UnsupportedType(opcode, reader.pos - 1));
+                }
+                // Size hints, not needed to read or rewrite the file
+                OPCODE_RESIZEDB => {
+                    let keys = reader.len()?;
+                    let expires = reader.len()?;
+                    return Ok(Some(Item::ResizeDb { keys, expires }));
+                }
+                OPCODE_SLOT_INFO => {
+                    reader.len()?;
+                    reader.len()?;
+                    reader.len()?;
+                    start = reader.pos;
+                }
+                // Prefixes of the following entry
+                OPCODE_EXPIRETIME => {
+                    reader.skip_len(4)?;
+                    expires = true;
+                }
+                OPCODE_EXPIRETIME_MS => {
+                    reader.skip_len(8)?;
+                    expires = true;
+                }
+                OPCODE_FREQ => reader.skip_len(1)?,
+                OPCODE_IDLE => {
+                    reader.len()?;
+                }
+                value_type => {
+                    reader.skip_string()?;
+                    reader.skip_value(value_type)?;
+                    return Ok(Some(Item::Entry {
+                        db: self.db,
+                        value_type,
+                        expires,
+                        raw: &reader.data[start..reader.pos],
+                    }));
+                }
+            }
+        }
+    }
+}
+
+/// Data type name of an RDB value type, one of `TYPE_NAMES`
+pub fn type_name(value_type: u8) -> &'static str {
+    match value_type {
+        0 => "string",
+        1 | 10 | 14 | 18 => "list",
+        2 | 11 | 20 => "set",
+        3 | 5 | 12 | 17 => "zset",
+        4 | 9 | 13 | 16 | 24 | 25 => "hash",
+        15 | 19 | 21 => "stream",
+        _ => "module",
+    }
+}
+
+/// Validate the RDB magic and return the version from the header
+pub fn parse_header(data: &[u8]) -> Result<u32, RdbError> {
+    if !data.starts_with(MAGIC) {
+        return Err(RdbError::InvalidMagic);
+    }
+    let version = data
+        .get(MAGIC.len()..HEADER_LEN)
+        .ok_or(RdbError::UnexpectedEof(data.len()))?;
+
+    let version = String::from_utf8_lossy(version);
+    version
+        .parse()
+        .map_err(|_| RdbError::InvalidVersion(version.into_owned()))
+}
+
+/// Build an RDB file containing only the keys of database `db`
+///
+/// Auxiliary fields, function libraries and module data are kept. Returns the new
+/// file and the number of keys it contains.
+pub fn filter_database(data: &[u8], db: u64) -> Result<(Vec<u8>, u64), RdbError> {
+    let mut parser = Parser::new(data)?;
+    let mut out = data[..HEADER_LEN].to_vec();
+    let mut keys = 0;
+
+    while let Some(item) = parser.next_item()? {
+        match item {
+            Item::Global(raw) => out.extend_from_slice(raw),
+            Item::Entry {
+                db: entry_db, raw, ..
+            } if entry_db == db => {
+                if keys == 0 {
+                    out.push(OPCODE_SELECTDB);
+                    write_length(&mut out, db);
+                }
+                out.extend_from_slice(raw);
+                keys += 1;
+            }
+            Item::Entry { .. } | Item::SelectDb(_) | Item::ResizeDb { .. } | Item::Eof => {}
+        }
+    }
+
+    out.push(OPCODE_EOF);
+    if parser.version() >= CHECKSUM_VERSION {
+        let checksum = crc64(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+    }
+
+    Ok((out, keys))
+}
+
+/// Overview of an RDB file, possibly only of its first bytes
+#[derive(Debug)]
+pub struct Summary {
+    pub version: u32,
+    /// Auxiliary fields like redis-ver, ctime and used-mem, in file order
+    pub aux: Vec<(String, String)>,
+    pub databases: BTreeMap<u64, DatabaseSummary>,
+    /// Whether the data reached the end of the file
+    pub complete: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct DatabaseSummary {
+    /// Number of keys and keys with an expiry, as recorded by Redis
+    pub keys_hint: Option<u64>,
+    pub expires_hint: Option<u64>,
+    /// Number of entries found in the data
+    pub entries: u64,
+    /// Number of entries found with an expiry
+    pub expires: u64,
+    /// Number of entries found by data type name
+    pub types: BTreeMap<&'static str, u64>,
+}
+
+/// Summarize an RDB file
+///
+/// `data` may be only the start of the file, e.g. from a ranged download. The
+/// summary then covers what was read and is marked incomplete.
+pub fn summarize(data: &[u8]) -> Result<Summary, RdbError> {
+    let mut parser = Parser::new(data)?;
+    let mut summary = Summary {
+        version: parser.version(),
+        aux: Vec::new(),
+        databases: BTreeMap::new(),
+        complete: false,
+    };
+    let mut db = 0;
+
+    loop {
+        let item = match parser.next_item() {
+            Ok(Some(item)) => item,
+            Ok(None) => break,
+            Err(RdbError::UnexpectedEof(_)) => return Ok(summary),
+            Err(e) => return Err(e),
+        };
+
+        match item {
+            Item::Global(raw) if raw.first() == Some(&OPCODE_AUX) => {
+                let mut reader = Reader { data: raw, pos: 1 };
+                let key = reader.string()?;
+                let value = reader.string()?;
+                summary.aux.push((
+                    String::from_utf8_lossy(&key).into_owned(),
+                    String::from_utf8_lossy(&value).into_owned(),
+                ));
+            }
+            Item::SelectDb(selected) => {
+                db = selected;
+                summary.databases.entry(db).or_default();
+            }
+            Item::ResizeDb { keys, expires } => {
+                let database = summary.databases.entry(db).or_default();
+                database.keys_hint = Some(keys);
+                database.expires_hint = Some(expires);
+            }
+            Item::Entry {
+                db,
+                value_type,
+                expires,
+                ..
+            } => {
+                let database = summary.databases.entry(db).or_default();
+                database.entries += 1;
+                database.expires += u64::from(expires);
+                *database.types.entry(type_name(value_type)).or_default() += 1;
+            }
+            Item::Global(_) => {}
+            Item::Eof => summary.complete = true,
+        }
+    }
+
+    Ok(summary)
+}
+
+fn write_length(out: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.push(0x40 | (len >> 8) as u8);
+        out.push(len as u8);
+    } else if let Ok(len) = u32::try_from(len) {
+        out.push(0x80);
+        out.extend_from_slice(&len.to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+/// Lookup table of the reflected CRC-64-Jones polynomial used by Redis
+const CRC64_TABLE: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x95ac_9329_ac4b_c9b5
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC64 checksum as stored at the end of an RDB file
+fn crc64(data: &[u8]) -> u64 {
+    data.iter().fold(0, |crc, byte| {
+        CRC64_TABLE[((crc ^ u64::from(*byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// LZF encoding of `data` as literal runs only
+    fn lzf_literals(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in data.chunks(32) {
+            out.push(chunk.len() as u8 - 1);
+            out.extend_from_slice(chunk);
+        }
+        out
+    }
+
+    fn string(out: &mut Vec<u8>, value: &[u8]) {
+        write_length(out, value.len() as u64);
+        out.extend_from_slice(value);
+    }
+
+    /// RDB file with an aux field, a key in db 0 and a key with expiry in db 1
+    fn sample_rdb() -> Vec<u8> {
+        let mut rdb = b"REDIS0011".to_vec();
+        rdb.push(OPCODE_AUX);
+        string(&mut rdb, b"redis-ver");
+        string(&mut rdb, b"7.2.4");
+        rdb.push(OPCODE_SELECTDB);
+        write_length(&mut rdb, 0);
+        rdb.extend_from_slice(&[OPCODE_RESIZEDB, 1, 0]);
+        rdb.push(0);
+        string(&mut rdb, b"a");
+        string(&mut rdb, b"1");
+        rdb.push(OPCODE_SELECTDB);
+        write_length(&mut rdb, 1);
+        rdb.extend_from_slice(&[OPCODE_RESIZEDB, 1, 1]);
+        rdb.push(OPCODE_EXPIRETIME_MS);
+        rdb.extend_from_slice(&1_700_000_000_000u64.to_le_bytes());
+        rdb.push(0);
+        string(&mut rdb, b"b");
+        string(&mut rdb, b"2");
+        rdb.push(OPCODE_EOF);
+        let checksum = crc64(&rdb);
+        rdb.extend_from_slice(&checksum.to_le_bytes());
+        rdb
+    }
+
+    #[test]
+    fn lzf_literal_round_trip() {
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let compressed = lzf_literals(&data);
+        assert_eq!(
+            lzf_decompress(&compressed, data.len() as u64),
+            Some(data.clone())
+        );
+    }
+
+    #[test]
+    fn lzf_back_references() {
+        // "abc", then 6 bytes from 3 back
+        let compressed = [2, b'a', b'b', b'c', 4 << 5, 2];
+        assert_eq!(lzf_decompress(&compressed, 9), Some(b"abcabcabc".to_vec()));
+
+        // "x", then a run extended by another byte: 7 + 3 + 2 bytes from 1 back
+        let compressed = [0, b'x', 7 << 5, 3, 0];
+        assert_eq!(lzf_decompress(&compressed, 13), Some(vec![b'x'; 13]));
+    }
+
+    #[test]
+    fn lzf_rejects_invalid_input() {
+        let compressed = [2, b'a', b'b', b'c', 4 << 5, 2];
+
+        // Truncated literal run and back reference
+        assert_eq!(lzf_decompress(&compressed[..3], 3), None);
+        assert_eq!(lzf_decompress(&compressed[..5], 9), None);
+        // Back reference before the start of the output
+        assert_eq!(lzf_decompress(&[4 << 5, 0], 6), None);
+        // Decompressed length differing from the recorded one
+        assert_eq!(lzf_decompress(&compressed, 8), None);
+        assert_eq!(lzf_decompress(&compressed, 10), None);
+        assert_eq!(lzf_decompress(&compressed, u64::MAX), None);
+    }
+
+    #[test]
+    fn parse_header_versions() {
+        assert_eq!(parse_header(b"REDIS0011").ok(), Some(11));
+        assert_eq!(parse_header(b"REDIS0009\xff").ok(), Some(9));
+        assert!(matches!(
+            parse_header(b"REDIS00xx"),
+            Err(RdbError::InvalidVersion(version)) if version == "00xx"
+        ));
+    }
+
+    #[test]
+    fn parse_header_short_buffers() {
+        assert!(matches!(parse_header(b""), Err(RdbError::InvalidMagic)));
+        assert!(matches!(parse_header(b"RED"), Err(RdbError::InvalidMagic)));
+        assert!(matches!(
+            parse_header(b"DUMP0011"),
+            Err(RdbError::InvalidMagic)
+        ));
+        assert!(matches!(
+            parse_header(b"REDIS"),
+            Err(RdbError::UnexpectedEof(5))
+        ));
+        assert!(matches!(
+            parse_header(b"REDIS001"),
+            Err(RdbError::UnexpectedEof(8))
+        ));
+    }
+
+    #[test]
+    fn crc64_check_value() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+    }
+
+    #[test]
+    fn filter_database_keeps_only_selected_keys() {
+        let (filtered, keys) = filter_database(&sample_rdb(), 1).unwrap();
+        assert_eq!(keys, 1);
+
+        let mut expected = b"REDIS0011".to_vec();
+        expected.push(OPCODE_AUX);
+        string(&mut expected, b"redis-ver");
+        string(&mut expected, b"7.2.4");
+        expected.extend_from_slice(&[OPCODE_SELECTDB, 1, OPCODE_EXPIRETIME_MS]);
+        expected.extend_from_slice(&1_700_000_000_000u64.to_le_bytes());
+        expected.push(0);
+        string(&mut expected, b"b");
+        string(&mut expected, b"2");
+        expected.push(OPCODE_EOF);
+        assert_eq!(filtered[..expected.len()], expected[..]);
+
+        // The trailer is the checksum of everything before it
+        let (body, trailer) = filtered.split_at(expected.len());
+        assert_eq!(trailer, crc64(body).to_le_bytes());
+
+        let summary = summarize(&filtered).unwrap();
+        assert!(summary.complete);
+        assert_eq!(
+            summary.aux,
+            [("redis-ver".to_string(), "7.2.4".to_string())]
+        );
+        assert_eq!(summary.databases.keys().collect::<Vec<_>>(), [&1]);
+        assert_eq!(summary.databases[&1].expires, 1);
+    }
+
+    #[test]
+    fn filter_database_without_matching_keys() {
+        let (filtered, keys) = filter_database(&sample_rdb(), 5).unwrap();
+        assert_eq!(keys, 0);
+
+        let summary = summarize(&filtered).unwrap();
+        assert!(summary.complete);
+        assert!(summary.databases.is_empty());
+        assert_eq!(summary.aux.len(), 1);
+    }
+
+    #[test]
+    fn filter_database_without_checksum() {
+        let mut rdb = b"REDIS0004".to_vec();
+        rdb.push(0);
+        string(&mut rdb, b"a");
+        string(&mut rdb, b"1");
+        rdb.push(OPCODE_EOF);
+
+        let (filtered, keys) = filter_database(&rdb, 0).unwrap();
+        assert_eq!(keys, 1);
+        assert_eq!(filtered.last(), Some(&OPCODE_EOF));
+    }
+}
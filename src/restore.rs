@@ -0,0 +1,349 @@
+//! Restore helpers
+//!
+//! This module writes a downloaded backup to disk so it is immediately usable by
+//! the Redis process: the file is written next to the target, gets its mode and
+//! ownership applied, and is then atomically renamed into place.
+//!
+//! Backups are written as they are downloaded, and decompressed on the way, so
+//! only a buffer of them is held in memory at a time.
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::fs::Permissions;
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+
+use crate::compression;
+use crate::dump::hex;
+use crate::storage::ObjectReader;
+
+/// Bytes read from a download at a time
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// File ownership to apply to a restored file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// Options controlling where and how a backup is restored
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    /// Object key of the backup, defaults to the newest backup of this node
+    pub key: Option<String>,
+    /// File to write, defaults to `dump_path` or `data_path/dump_filename`
+    pub target_file: Option<PathBuf>,
+    /// Ownership of the restored file
+    pub owner: Option<Owner>,
+    /// Permission bits of the restored file
+    pub mode: Option<u32>,
+    /// Only restore the keys of this database
+    pub db: Option<u64>,
+}
+
+/// Parse an octal file mode like "0640"
+pub fn parse_mode(mode: &str) -> Result<u32, String> {
+    let digits = mode.trim_start_matches("0o");
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("Invalid file mode: {}", mode)),
+    }
+}
+
+/// Parse an owner like "redis:redis", "redis", ":redis" or "999:999"
+///
+/// User and group names are resolved through /etc/passwd and /etc/group.
+pub fn parse_owner(owner: &str) -> Result<Owner, String> {
+    let (user, group) = owner.split_once(':').unwrap_or((owner, ""));
+
+    let uid = match user {
+        "" => None,
+        user => Some(lookup_id("/etc/passwd", user).map_err(|e| e.to_string())?),
+    };
+    let gid = match group {
+        "" => None,
+        group => Some(lookup_id("/etc/group", group).map_err(|e| e.to_string())?),
+    };
+
+    if uid.is_none() && gid.is_none() {
+        return Err(format!("Invalid owner: {}", owner));
+    }
+
+    Ok(Owner { uid, gid })
+}
+
+/// Resolve a user or group name to its numeric id, numeric values are taken as is
+fn lookup_id(database: &str, name: &str) -> Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+
+    let content = std::fs::read_to_string(database)
+        .with_context(|| format!("Failed to read {} to resolve {}", database, name))?;
+
+    // Both files use "name:password:id:..." entries
+    content
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.split(':');
+            if fields.next()? != name {
+                return None;
+            }
+            fields.nth(1)?.parse().ok()
+        })
+        .with_context(|| format!("Unknown name {} in {}", name, database))
+}
+
+/// A backup downloaded next to its target, not moved into place yet
+#[derive(Debug)]
+pub struct DownloadedFile {
+    tmp: PathBuf,
+    /// SHA-256 of the object as stored, before it was decompressed
+    pub checksum: String,
+    /// Bytes written, after decompression
+    pub size: u64,
+}
+
+impl DownloadedFile {
+    /// Read the downloaded file back into memory
+    pub async fn read(&self) -> Result<Vec<u8>> {
+        fs::read(&self.tmp)
+            .await
+            .with_context(|| format!("Failed to read {:?}", self.tmp))
+    }
+
+    /// Apply mode and ownership to the file and move it into place at `target`
+    pub async fn install(
+        self,
+        target: &Path,
+        mode: Option<u32>,
+        owner: Option<Owner>,
+    ) -> Result<()> {
+        install(&self.tmp, target, mode, owner).await
+    }
+
+    /// Remove the file, leaving the target untouched
+    pub async fn discard(self) {
+        let _ = fs::remove_file(&self.tmp).await;
+    }
+}
+
+/// File a restore of `target` is written to before it is moved into place
+fn tmp_path(target: &Path) -> PathBuf {
+    let mut tmp = target.as_os_str().to_owned();
+    tmp.push(".restore-tmp");
+    PathBuf::from(tmp)
+}
+
+/// Download an object into a file next to `target`, decompressing it if `compressed`
+///
+/// The object is hashed as it is read, for the check against its checksum before
+/// the file is installed.
+pub async fn download_restored_file(
+    target: &Path,
+    mut reader: ObjectReader,
+    compressed: bool,
+) -> Result<DownloadedFile> {
+    let tmp = tmp_path(target);
+    let file = fs::File::create(&tmp)
+        .await
+        .with_context(|| format!("Failed to write {:?}", tmp))?
+        .into_std()
+        .await;
+
+    // Decompression and writes block, so they run on their own thread
+    let (sender, receiver) = mpsc::channel(4);
+    let path = tmp.clone();
+    let writer =
+        tokio::task::spawn_blocking(move || write_chunks(receiver, file, compressed, &path));
+
+    let mut hasher = Sha256::new();
+    let read = async {
+        let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+        loop {
+            let n = reader
+                .read(&mut buffer)
+                .await
+                .context("Failed to download backup")?;
+            if n == 0 {
+                return Ok(());
+            }
+            hasher.update(&buffer[..n]);
+            // A writer that stopped reports why below
+            if sender
+                .send(Bytes::copy_from_slice(&buffer[..n]))
+                .await
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+    }
+    .await;
+    drop(sender);
+    let written = writer.await.context("Restore writer task failed")?;
+
+    match read.and(written) {
+        Ok(size) => Ok(DownloadedFile {
+            tmp,
+            checksum: hex(&hasher.finalize()),
+            size,
+        }),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp).await;
+            Err(e)
+        }
+    }
+}
+
+/// Write the chunks received to `file`, decompressing them if `compressed`
+fn write_chunks(
+    receiver: mpsc::Receiver<Bytes>,
+    file: std::fs::File,
+    compressed: bool,
+    path: &Path,
+) -> Result<u64> {
+    let mut input = ChunkReader {
+        receiver,
+        chunk: Bytes::new(),
+    };
+    let mut output = BufWriter::new(file);
+    let size = if compressed {
+        compression::decompress_into(&mut input, &mut output)
+            .with_context(|| format!("Failed to decompress backup into {:?}", path))?
+    } else {
+        io::copy(&mut input, &mut output).with_context(|| format!("Failed to write {:?}", path))?
+    };
+    output
+        .flush()
+        .with_context(|| format!("Failed to write {:?}", path))?;
+    Ok(size)
+}
+
+/// Blocking reader of the chunks sent over a channel, ending when it is closed
+struct ChunkReader {
+    receiver: mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+/// Write restored data to `target`, applying mode and ownership before moving it into place
+pub async fn write_restored_file(
+    target: &Path,
+    data: &[u8],
+    mode: Option<u32>,
+    owner: Option<Owner>,
+) -> Result<()> {
+    let tmp = tmp_path(target);
+    if let Err(e) = fs::write(&tmp, data).await {
+        let _ = fs::remove_file(&tmp).await;
+        return Err(e).with_context(|| format!("Failed to write {:?}", tmp));
+    }
+    install(&tmp, target, mode, owner).await
+}
+
+/// Apply mode and ownership to `tmp` and move it to `target`, removing it on errors
+async fn install(tmp: &Path, target: &Path, mode: Option<u32>, owner: Option<Owner>) -> Result<()> {
+    let result = async {
+        if let Some(mode) = mode {
+            fs::set_permissions(tmp, Permissions::from_mode(mode))
+                .await
+                .with_context(|| format!("Failed to set mode {:o} on {:?}", mode, tmp))?;
+        }
+
+        if let Some(owner) = owner {
+            std::os::unix::fs::chown(tmp, owner.uid, owner.gid).map_err(|e| {
+                if e.kind() == ErrorKind::PermissionDenied {
+                    anyhow::anyhow!(
+                        "Setting ownership of {:?} requires elevated privileges (run as root or with CAP_CHOWN): {}",
+                        target,
+                        e
+                    )
+                } else {
+                    anyhow::anyhow!("Failed to set ownership of {:?}: {}", tmp, e)
+                }
+            })?;
+        }
+
+        fs::rename(tmp, target)
+            .await
+            .with_context(|| format!("Failed to move restored file to {:?}", target))
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = fs::remove_file(tmp).await;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dump::sha256_hex;
+    use crate::testing::TempDir;
+
+    #[tokio::test]
+    async fn backups_are_decompressed_as_downloaded() {
+        let dir = TempDir::new("restore-stream");
+        let target = dir.join("dump.rdb");
+        let dump: Vec<u8> = (0..200_000u32)
+            .flat_map(|i| (i % 97).to_le_bytes())
+            .collect();
+        let stored = [
+            compression::compress(&dump[..300_000], 6).unwrap(),
+            compression::compress(&dump[300_000..], 6).unwrap(),
+        ]
+        .concat();
+
+        let reader: ObjectReader = Box::new(io::Cursor::new(stored.clone()));
+        let downloaded = download_restored_file(&target, reader, true).await.unwrap();
+        assert_eq!(downloaded.checksum, sha256_hex(&stored));
+        assert_eq!(downloaded.size, dump.len() as u64);
+        assert!(!target.exists());
+
+        downloaded
+            .install(&target, Some(0o640), None)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), dump);
+        let mode = std::fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        assert!(!tmp_path(&target).exists());
+    }
+
+    #[tokio::test]
+    async fn corrupt_backups_leave_nothing_behind() {
+        let dir = TempDir::new("restore-corrupt");
+        let target = dir.join("dump.rdb");
+        std::fs::write(&target, b"REDIS0011 current").unwrap();
+
+        let reader: ObjectReader = Box::new(io::Cursor::new(b"REDIS0011 not gzip".to_vec()));
+        let error = download_restored_file(&target, reader, true)
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("Failed to decompress backup"));
+        assert!(!tmp_path(&target).exists());
+        assert_eq!(std::fs::read(&target).unwrap(), b"REDIS0011 current");
+    }
+}
@@ -0,0 +1,34 @@
+//! Graceful shutdown handling
+//!
+//! This module turns SIGTERM/SIGINT into a shutdown request that is broadcast
+//! over a watch channel, so the backup loop and the metrics server can finish
+//! their in-flight work before the process exits.
+
+use anyhow::Result;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+use tracing::info;
+
+/// Listen for SIGTERM and SIGINT and request a shutdown on the first one received
+pub fn spawn_signal_listener(shutdown: watch::Sender<bool>) -> Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+            _ = sigint.recv() => info!("Received SIGINT, shutting down"),
+        }
+        shutdown.send_replace(true);
+    });
+
+    Ok(())
+}
+
+/// Wait until a shutdown has been requested
+pub async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    // An error means the sender is gone, so no shutdown can be requested anymore
+    if shutdown.wait_for(|requested| *requested).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
@@ -0,0 +1,62 @@
+//! Local backup state
+//!
+//! With `backup.state_file` set, the outcome of the last upload of every node is
+//! written to a small JSON file after each cycle and read back on startup. A
+//! restarted process then knows which files were already uploaded and when the
+//! last backup succeeded, without asking storage.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::fs;
+
+/// Last upload of one node, or shard
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeState {
+    /// Object key of the backup in the first destination of the cycle
+    pub key: String,
+    /// Modification time of the newest backed up file
+    pub modified: DateTime<Utc>,
+    /// Total size of the backed up files
+    pub size: u64,
+    /// SHA-256 of the uploaded data
+    pub checksum: String,
+    /// Time the upload finished
+    pub uploaded: DateTime<Utc>,
+}
+
+/// Contents of the state file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BackupState {
+    #[serde(default)]
+    pub nodes: BTreeMap<String, NodeState>,
+    /// Time of the last successful backup cycle
+    pub last_success: Option<DateTime<Utc>>,
+}
+
+impl BackupState {
+    /// Read the state from `path`, empty if the file does not exist yet
+    pub async fn load(path: &Path) -> Result<Self> {
+        let content = match fs::read(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", path)),
+        };
+        serde_json::from_slice(&content).with_context(|| format!("Failed to parse {:?}", path))
+    }
+
+    /// Write the state to `path`, replacing the file atomically
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        fs::write(&temp, serde_json::to_vec_pretty(self)?)
+            .await
+            .with_context(|| format!("Failed to write {:?}", temp))?;
+        fs::rename(&temp, path)
+            .await
+            .with_context(|| format!("Failed to replace {:?}", path))?;
+        Ok(())
+    }
+}
@@ -2,21 +2,59 @@ use anyhow::Result;
 use async_trait::async_trait;
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use gcloud_storage::client::google_cloud_auth::credentials::CredentialsFile;
 use gcloud_storage::client::{Client as GcsClient, ClientConfig};
+use gcloud_storage::http::objects::Object;
+use gcloud_storage::http::objects::upload::{Media, UploadObjectRequest, UploadType};
+use gcloud_storage::http::resumable_upload_client::{ChunkSize, ResumableUploadClient};
+use reqwest_middleware::ClientWithMiddleware;
+use std::collections::HashMap;
+use std::io;
+use tokio_util::io::StreamReader;
 
-use super::{BackupMetadata, StorageBackend};
+use super::{
+    BackupMetadata, ObjectReader, PartUpload, StorageBackend, content_type, http_client_builder,
+};
 use crate::backup::BackupError;
+use crate::config::{GcsConfig, StorageClientConfig};
 
 pub struct GcsStorage {
     client: GcsClient,
 }
 
 impl GcsStorage {
-    pub async fn new() -> Result<Self> {
-        let client_config = ClientConfig::default()
-            .with_auth()
-            .await
-            .map_err(|e| BackupError::Gcs(e.to_string()))?;
+    pub async fn new(config: &GcsConfig, http: &StorageClientConfig) -> Result<Self> {
+        let mut client_config = ClientConfig::default();
+        if let Some(endpoint) = &config.endpoint {
+            client_config.storage_endpoint = endpoint.trim_end_matches('/').to_string();
+        }
+        // The default client already honors HTTPS_PROXY and NO_PROXY
+        if http.proxy.is_some() || http.ca_bundle.is_some() || http.insecure_skip_verify {
+            client_config.http = Some(http_client(http).await?);
+        }
+
+        let mut client_config = if config.anonymous {
+            client_config.anonymous()
+        } else if let Some(path) = &config.credentials_file {
+            let credentials = CredentialsFile::new_from_file(path.to_string_lossy().into_owned())
+                .await
+                .map_err(|e| BackupError::Gcs(format!("Failed to read {:?}: {}", path, e)))?;
+            client_config
+                .with_credentials(credentials)
+                .await
+                .map_err(|e| BackupError::Gcs(e.to_string()))?
+        } else {
+            client_config
+                .with_auth()
+                .await
+                .map_err(|e| BackupError::Gcs(e.to_string()))?
+        };
+
+        // An explicit project id takes precedence over the detected one
+        if let Some(project_id) = &config.project_id {
+            client_config.project_id = Some(project_id.clone());
+        }
 
         let client = GcsClient::new(client_config);
 
@@ -24,25 +62,269 @@ impl GcsStorage {
     }
 }
 
+/// How an object is uploaded, with its metadata and tags as custom metadata
+fn upload_type(
+    key: &str,
+    metadata: &HashMap<String, String>,
+    tags: &HashMap<String, String>,
+) -> UploadType {
+    // GCS has no object tags, they are stored as custom metadata
+    let mut metadata = metadata.clone();
+    metadata.extend(tags.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+    // Custom metadata requires a multipart upload carrying the object resource
+    let content_type = content_type(key);
+    if metadata.is_empty() {
+        UploadType::Simple(Media {
+            content_type: content_type.into(),
+            ..Media::new(key.to_string())
+        })
+    } else {
+        UploadType::Multipart(Box::new(Object {
+            name: key.to_string(),
+            content_type: Some(content_type.to_string()),
+            metadata: Some(metadata),
+            ..Default::default()
+        }))
+    }
+}
+
+/// A resumable upload of an object, sent in chunks
+///
+/// The last chunk is held back until the next one or the completion, as only it
+/// may carry the size of the object.
+struct GcsPartUpload {
+    client: ResumableUploadClient,
+    uploaded: u64,
+    pending: Option<Bytes>,
+}
+
+impl GcsPartUpload {
+    /// Upload a chunk, the last one if `last`
+    async fn upload_chunk(&mut self, data: Bytes, last: bool) -> Result<()> {
+        let size = data.len() as u64;
+        let end = self.uploaded + size;
+        let total = last.then_some(end);
+        // An empty last chunk only announces the size of the object
+        let range = match size {
+            0 => ChunkSize::new(end, end, total),
+            _ => ChunkSize::new(self.uploaded, end - 1, total),
+        };
+        self.client
+            .upload_multiple_chunk(data, &range)
+            .await
+            .map_err(|e| BackupError::Gcs(e.to_string()))?;
+        self.uploaded = end;
+        Ok(())
+    }
+}
+
 #[async_trait]
-impl StorageBackend for GcsStorage {
-    async fn upload(&self, bucket: &str, key: &str, data: Bytes) -> Result<()> {
-        use gcloud_storage::http::objects::upload::{Media, UploadObjectRequest, UploadType};
+impl PartUpload for GcsPartUpload {
+    async fn upload_part(&mut self, data: Bytes) -> Result<()> {
+        if let Some(previous) = self.pending.replace(data) {
+            self.upload_chunk(previous, false).await?;
+        }
+        Ok(())
+    }
+
+    async fn complete(mut self: Box<Self>) -> Result<()> {
+        let last = self.pending.take().unwrap_or_default();
+        self.upload_chunk(last, true).await
+    }
+
+    async fn abort(self: Box<Self>) -> Result<()> {
+        self.client
+            .cancel()
+            .await
+            .map_err(|e| BackupError::Gcs(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// HTTP client with the configured proxy and TLS settings
+///
+/// Token requests of the credentials use their own client, which only honors
+/// HTTPS_PROXY and NO_PROXY and always verifies certificates.
+async fn http_client(config: &StorageClientConfig) -> Result<ClientWithMiddleware> {
+    let client = http_client_builder(config)
+        .await?
+        .build()
+        .map_err(|e| BackupError::Gcs(e.to_string()))?;
+    Ok(reqwest_middleware::ClientBuilder::new(client).build())
+}
 
-        let upload_type = UploadType::Simple(Media::new(key.to_string()));
+#[async_trait]
+impl StorageBackend for GcsStorage {
+    async fn upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Bytes,
+        metadata: &HashMap<String, String>,
+        tags: &HashMap<String, String>,
+    ) -> Result<()> {
         let req = UploadObjectRequest {
             bucket: bucket.to_string(),
             ..Default::default()
         };
 
         self.client
-            .upload_object(&req, data.to_vec(), &upload_type)
+            .upload_object(&req, data.to_vec(), &upload_type(key, metadata, tags))
             .await
             .map_err(|e| BackupError::Gcs(e.to_string()))?;
 
         Ok(())
     }
 
+    async fn start_upload<'a>(
+        &'a self,
+        bucket: &str,
+        key: &str,
+        metadata: &HashMap<String, String>,
+        tags: &HashMap<String, String>,
+    ) -> Result<Box<dyn PartUpload + 'a>> {
+        let req = UploadObjectRequest {
+            bucket: bucket.to_string(),
+            ..Default::default()
+        };
+
+        let client = self
+            .client
+            .prepare_resumable_upload(&req, &upload_type(key, metadata, tags))
+            .await
+            .map_err(|e| BackupError::Gcs(e.to_string()))?;
+
+        Ok(Box::new(GcsPartUpload {
+            client,
+            uploaded: 0,
+            pending: None,
+        }))
+    }
+
+    async fn download(&self, bucket: &str, key: &str) -> Result<Bytes> {
+        use gcloud_storage::http::Error;
+        use gcloud_storage::http::objects::download::Range;
+        use gcloud_storage::http::objects::get::GetObjectRequest;
+
+        let req = GetObjectRequest {
+            bucket: bucket.to_string(),
+            object: key.to_string(),
+            ..Default::default()
+        };
+
+        let data = match self.client.download_object(&req, &Range::default()).await {
+            Ok(data) => data,
+            Err(Error::Response(e)) if e.code == 404 => {
+                return Err(BackupError::NotFound(key.to_string()).into());
+            }
+            Err(e) => return Err(BackupError::Gcs(e.to_string()).into()),
+        };
+
+        Ok(Bytes::from(data))
+    }
+
+    async fn download_stream(&self, bucket: &str, key: &str) -> Result<ObjectReader> {
+        use gcloud_storage::http::Error;
+        use gcloud_storage::http::objects::download::Range;
+        use gcloud_storage::http::objects::get::GetObjectRequest;
+
+        let req = GetObjectRequest {
+            bucket: bucket.to_string(),
+            object: key.to_string(),
+            ..Default::default()
+        };
+
+        let chunks = match self
+            .client
+            .download_streamed_object(&req, &Range::default())
+            .await
+        {
+            Ok(chunks) => chunks,
+            Err(Error::Response(e)) if e.code == 404 => {
+                return Err(BackupError::NotFound(key.to_string()).into());
+            }
+            Err(e) => return Err(BackupError::Gcs(e.to_string()).into()),
+        };
+
+        let chunks = chunks.map_err(|e| io::Error::other(BackupError::Gcs(e.to_string())));
+        Ok(Box::new(StreamReader::new(Box::pin(chunks))))
+    }
+
+    async fn download_range(&self, bucket: &str, key: &str, start: u64, end: u64) -> Result<Bytes> {
+        use gcloud_storage::http::Error;
+        use gcloud_storage::http::objects::download::Range;
+        use gcloud_storage::http::objects::get::GetObjectRequest;
+
+        if start > end {
+            return Ok(Bytes::new());
+        }
+
+        let req = GetObjectRequest {
+            bucket: bucket.to_string(),
+            object: key.to_string(),
+            ..Default::default()
+        };
+
+        let data = match self
+            .client
+            .download_object(&req, &Range(Some(start), Some(end)))
+            .await
+        {
+            Ok(data) => data,
+            Err(Error::Response(e)) if e.code == 404 => {
+                return Err(BackupError::NotFound(key.to_string()).into());
+            }
+            Err(e) => return Err(BackupError::Gcs(e.to_string()).into()),
+        };
+
+        Ok(Bytes::from(data))
+    }
+
+    async fn object_metadata(&self, bucket: &str, key: &str) -> Result<HashMap<String, String>> {
+        use gcloud_storage::http::objects::get::GetObjectRequest;
+
+        let req = GetObjectRequest {
+            bucket: bucket.to_string(),
+            object: key.to_string(),
+            ..Default::default()
+        };
+
+        let object = self
+            .client
+            .get_object(&req)
+            .await
+            .map_err(|e| BackupError::Gcs(e.to_string()))?;
+
+        Ok(object.metadata.unwrap_or_default())
+    }
+
+    async fn head_object(&self, bucket: &str, key: &str) -> Result<Option<BackupMetadata>> {
+        use gcloud_storage::http::Error;
+        use gcloud_storage::http::objects::get::GetObjectRequest;
+
+        let req = GetObjectRequest {
+            bucket: bucket.to_string(),
+            object: key.to_string(),
+            ..Default::default()
+        };
+
+        let object = match self.client.get_object(&req).await {
+            Ok(object) => object,
+            Err(Error::Response(e)) if e.code == 404 => return Ok(None),
+            Err(e) => return Err(BackupError::Gcs(e.to_string()).into()),
+        };
+
+        Ok(Some(BackupMetadata {
+            key: object.name,
+            timestamp: object
+                .time_created
+                .and_then(|created| DateTime::from_timestamp(created.unix_timestamp(), 0))
+                .unwrap_or_else(Utc::now),
+            size: object.size,
+        }))
+    }
+
     async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<BackupMetadata>> {
         use gcloud_storage::http::objects::list::ListObjectsRequest;
 
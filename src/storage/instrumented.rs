@@ -0,0 +1,403 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{StreamExt, stream};
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::RwLock;
+use tokio::time::Sleep;
+
+use super::{BackupMetadata, ObjectReader, PartUpload, StorageBackend};
+use crate::backup::BackupError;
+use crate::metrics::{Metrics, write_metrics};
+
+/// Storage backend recording the duration and errors of every operation, and
+/// failing operations that exceed the timeout
+pub struct InstrumentedStorage {
+    inner: Arc<dyn StorageBackend>,
+    backend: &'static str,
+    timeout: Duration,
+    metrics: Arc<RwLock<Metrics>>,
+}
+
+impl InstrumentedStorage {
+    pub fn new(
+        inner: Arc<dyn StorageBackend>,
+        backend: &'static str,
+        timeout: Duration,
+        metrics: Arc<RwLock<Metrics>>,
+    ) -> Self {
+        InstrumentedStorage {
+            inner,
+            backend,
+            timeout,
+            metrics,
+        }
+    }
+
+    async fn record<T>(
+        &self,
+        operation: &'static str,
+        future: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let result = match tokio::time::timeout(self.timeout, future).await {
+            Ok(result) => result,
+            Err(_) => Err(BackupError::Timeout {
+                operation,
+                timeout: self.timeout,
+            }
+            .into()),
+        };
+        let elapsed = start.elapsed();
+
+        let metrics = write_metrics(&self.metrics).await;
+        let labels = [self.backend, operation];
+        metrics
+            .storage_operation_duration_seconds
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+        if result.is_err() {
+            metrics
+                .storage_operation_errors_total
+                .with_label_values(&labels)
+                .inc();
+        }
+
+        result
+    }
+
+    /// Delete the keys of a single request of `delete_batch`
+    async fn delete_request(&self, bucket: &str, keys: &[String]) -> Vec<Result<()>> {
+        let start = Instant::now();
+        let request = self.inner.delete_batch(bucket, keys, 1);
+        let results = match tokio::time::timeout(self.timeout, request).await {
+            Ok(results) => results,
+            Err(_) => keys
+                .iter()
+                .map(|_| {
+                    Err(BackupError::Timeout {
+                        operation: "delete_batch",
+                        timeout: self.timeout,
+                    }
+                    .into())
+                })
+                .collect(),
+        };
+        let elapsed = start.elapsed();
+
+        // Every failed key counts as an error
+        let metrics = write_metrics(&self.metrics).await;
+        let labels = [self.backend, "delete_batch"];
+        metrics
+            .storage_operation_duration_seconds
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+        metrics
+            .storage_operation_errors_total
+            .with_label_values(&labels)
+            .inc_by(results.iter().filter(|r| r.is_err()).count() as u64);
+
+        results
+    }
+}
+
+/// Multipart upload recording every request like the storage it was started on
+struct InstrumentedUpload<'a> {
+    inner: Box<dyn PartUpload + 'a>,
+    storage: &'a InstrumentedStorage,
+}
+
+#[async_trait]
+impl PartUpload for InstrumentedUpload<'_> {
+    async fn upload_part(&mut self, data: Bytes) -> Result<()> {
+        self.storage
+            .record("upload_part", self.inner.upload_part(data))
+            .await
+    }
+
+    async fn complete(self: Box<Self>) -> Result<()> {
+        self.storage
+            .record("complete_upload", self.inner.complete())
+            .await
+    }
+
+    async fn abort(self: Box<Self>) -> Result<()> {
+        self.storage
+            .record("abort_upload", self.inner.abort())
+            .await
+    }
+}
+
+/// Streamed download failing its reads once the timeout of the download elapsed
+struct TimedReader {
+    inner: ObjectReader,
+    deadline: Pin<Box<Sleep>>,
+    timeout: Duration,
+}
+
+impl AsyncRead for TimedReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.deadline.as_mut().poll(cx).is_ready() {
+            let error = BackupError::Timeout {
+                operation: "download_stream",
+                timeout: self.timeout,
+            };
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, error)));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for InstrumentedStorage {
+    async fn upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Bytes,
+        metadata: &HashMap<String, String>,
+        tags: &HashMap<String, String>,
+    ) -> Result<()> {
+        self.record(
+            "upload",
+            self.inner.upload(bucket, key, data, metadata, tags),
+        )
+        .await
+    }
+
+    async fn start_upload<'a>(
+        &'a self,
+        bucket: &str,
+        key: &str,
+        metadata: &HashMap<String, String>,
+        tags: &HashMap<String, String>,
+    ) -> Result<Box<dyn PartUpload + 'a>> {
+        let inner = self
+            .record(
+                "start_upload",
+                self.inner.start_upload(bucket, key, metadata, tags),
+            )
+            .await?;
+        Ok(Box::new(InstrumentedUpload {
+            inner,
+            storage: self,
+        }))
+    }
+
+    async fn download(&self, bucket: &str, key: &str) -> Result<Bytes> {
+        self.record("download", self.inner.download(bucket, key))
+            .await
+    }
+
+    /// The timeout covers reading the object too, from the start of the download
+    async fn download_stream(&self, bucket: &str, key: &str) -> Result<ObjectReader> {
+        let deadline = Box::pin(tokio::time::sleep(self.timeout));
+        let inner = self
+            .record("download_stream", self.inner.download_stream(bucket, key))
+            .await?;
+        Ok(Box::new(TimedReader {
+            inner,
+            deadline,
+            timeout: self.timeout,
+        }))
+    }
+
+    async fn download_range(&self, bucket: &str, key: &str, start: u64, end: u64) -> Result<Bytes> {
+        self.record(
+            "download_range",
+            self.inner.download_range(bucket, key, start, end),
+        )
+        .await
+    }
+
+    async fn object_metadata(&self, bucket: &str, key: &str) -> Result<HashMap<String, String>> {
+        self.record("object_metadata", self.inner.object_metadata(bucket, key))
+            .await
+    }
+
+    async fn head_object(&self, bucket: &str, key: &str) -> Result<Option<BackupMetadata>> {
+        self.record("head_object", self.inner.head_object(bucket, key))
+            .await
+    }
+
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<BackupMetadata>> {
+        self.record("list", self.inner.list(bucket, prefix)).await
+    }
+
+    async fn delete(&self, bucket: &str, key: &str) -> Result<()> {
+        self.record("delete", self.inner.delete(bucket, key)).await
+    }
+
+    fn delete_batch_size(&self) -> usize {
+        self.inner.delete_batch_size()
+    }
+
+    /// Every request of the batch is timed on its own, so a hung request only
+    /// fails the keys it deletes
+    async fn delete_batch(
+        &self,
+        bucket: &str,
+        keys: &[String],
+        concurrency: usize,
+    ) -> Vec<Result<()>> {
+        let requests: Vec<_> = keys
+            .chunks(self.inner.delete_batch_size().max(1))
+            .map(|chunk| self.delete_request(bucket, chunk))
+            .collect();
+        stream::iter(requests)
+            .buffered(concurrency.max(1))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::get_default_config;
+
+    /// Deletes in batches of two, failing keys starting with "fail" and hanging on
+    /// batches with a key starting with "hang"
+    struct Deletes {
+        requests: std::sync::Mutex<Vec<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl StorageBackend for Deletes {
+        async fn upload(
+            &self,
+            _bucket: &str,
+            _key: &str,
+            _data: Bytes,
+            _metadata: &HashMap<String, String>,
+            _tags: &HashMap<String, String>,
+        ) -> Result<()> {
+            unreachable!()
+        }
+
+        async fn download(&self, _bucket: &str, _key: &str) -> Result<Bytes> {
+            unreachable!()
+        }
+
+        async fn download_range(
+            &self,
+            _bucket: &str,
+            _key: &str,
+            _start: u64,
+            _end: u64,
+        ) -> Result<Bytes> {
+            unreachable!()
+        }
+
+        async fn object_metadata(
+            &self,
+            _bucket: &str,
+            _key: &str,
+        ) -> Result<HashMap<String, String>> {
+            unreachable!()
+        }
+
+        async fn head_object(&self, _bucket: &str, _key: &str) -> Result<Option<BackupMetadata>> {
+            unreachable!()
+        }
+
+        async fn list(&self, _bucket: &str, _prefix: &str) -> Result<Vec<BackupMetadata>> {
+            unreachable!()
+        }
+
+        async fn delete(&self, _bucket: &str, _key: &str) -> Result<()> {
+            unreachable!()
+        }
+
+        fn delete_batch_size(&self) -> usize {
+            2
+        }
+
+        async fn delete_batch(
+            &self,
+            _bucket: &str,
+            keys: &[String],
+            _concurrency: usize,
+        ) -> Vec<Result<()>> {
+            self.requests.lock().unwrap().push(keys.to_vec());
+            if keys.iter().any(|key| key.starts_with("hang")) {
+                std::future::pending::<()>().await;
+            }
+            keys.iter()
+                .map(|key| match key.starts_with("fail") {
+                    true => Err(anyhow::anyhow!("{} failed", key)),
+                    false => Ok(()),
+                })
+                .collect()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delete_batch_times_every_request() {
+        let inner = Arc::new(Deletes {
+            requests: Default::default(),
+        });
+        let metrics = Metrics::new(&get_default_config().metrics).unwrap();
+        let metrics = Arc::new(RwLock::new(metrics));
+        let storage = InstrumentedStorage::new(
+            inner.clone(),
+            "s3",
+            Duration::from_secs(30),
+            metrics.clone(),
+        );
+
+        let keys: Vec<String> = ["a", "fail-b", "hang-c", "d", "e"]
+            .iter()
+            .map(|key| key.to_string())
+            .collect();
+        let results = storage.delete_batch("bucket", &keys, 2).await;
+        let results: Vec<_> = results
+            .iter()
+            .map(|result| result.as_ref().map(|_| ()).map_err(|e| e.to_string()))
+            .collect();
+        assert_eq!(
+            results,
+            [
+                Ok(()),
+                Err("fail-b failed".to_string()),
+                Err("Storage delete_batch timed out after 30s".to_string()),
+                Err("Storage delete_batch timed out after 30s".to_string()),
+                Ok(()),
+            ]
+        );
+        assert_eq!(
+            *inner.requests.lock().unwrap(),
+            [vec!["a", "fail-b"], vec!["hang-c", "d"], vec!["e"]]
+        );
+
+        let metrics = metrics.read().await;
+        let labels = ["s3", "delete_batch"];
+        assert_eq!(
+            metrics
+                .storage_operation_errors_total
+                .with_label_values(&labels)
+                .get(),
+            3
+        );
+        assert_eq!(
+            metrics
+                .storage_operation_duration_seconds
+                .with_label_values(&labels)
+                .get_sample_count(),
+            3
+        );
+    }
+}
@@ -1,84 +1,351 @@
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
 use async_trait::async_trait;
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
+use futures_util::{StreamExt, stream};
+use std::collections::HashMap;
 use std::fmt;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncRead;
+use tokio::sync::RwLock;
+use tracing::warn;
 
-use crate::storage::{gcs::GcsStorage, s3::S3Storage};
+use crate::backup::BackupError;
+use crate::config::{GcsConfig, S3Config, StorageClientConfig, StorageTarget};
+use crate::metrics::Metrics;
+use crate::storage::{gcs::GcsStorage, instrumented::InstrumentedStorage, s3::S3Storage};
 
 pub mod gcs;
+pub mod instrumented;
 pub mod s3;
+pub mod throttle;
+
+/// Smallest part of a multipart upload S3 accepts, except for the last one
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// GCS takes the chunks of a resumable upload in multiples of this size
+pub const PART_SIZE_MULTIPLE: u64 = 256 * 1024;
+
+/// Contents of an object, read as they are downloaded
+pub type ObjectReader = Box<dyn AsyncRead + Send + Unpin>;
 
 #[async_trait]
 pub trait StorageBackend: Send + Sync {
-    async fn upload(&self, bucket: &str, key: &str, data: Bytes) -> Result<()>;
+    async fn upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Bytes,
+        metadata: &HashMap<String, String>,
+        tags: &HashMap<String, String>,
+    ) -> Result<()>;
+
+    /// Start an upload of an object sent in parts, so it is never held as a whole
+    ///
+    /// Backends without multipart uploads collect the parts and upload them at once.
+    async fn start_upload<'a>(
+        &'a self,
+        bucket: &str,
+        key: &str,
+        metadata: &HashMap<String, String>,
+        tags: &HashMap<String, String>,
+    ) -> Result<Box<dyn PartUpload + 'a>> {
+        Ok(Box::new(BufferedUpload {
+            storage: self,
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            metadata: metadata.clone(),
+            tags: tags.clone(),
+            data: Vec::new(),
+        }))
+    }
+    /// Download an object, failing with `BackupError::NotFound` if it does not exist
+    async fn download(&self, bucket: &str, key: &str) -> Result<Bytes>;
+    /// Download an object like `download`, without holding it in memory as a whole
+    ///
+    /// A missing object fails here with `BackupError::NotFound`, errors while
+    /// reading fail the reads. Backends without streamed downloads read the
+    /// object at once.
+    async fn download_stream(&self, bucket: &str, key: &str) -> Result<ObjectReader> {
+        let data = self.download(bucket, key).await?;
+        Ok(Box::new(std::io::Cursor::new(data)))
+    }
+    /// Download bytes `start` to `end` of an object, or less if the object is shorter
+    ///
+    /// Both ends are inclusive, as in an HTTP `Range` header.
+    async fn download_range(&self, bucket: &str, key: &str, start: u64, end: u64) -> Result<Bytes>;
+    async fn object_metadata(&self, bucket: &str, key: &str) -> Result<HashMap<String, String>>;
+    /// Key, timestamp and size of an object, `None` if it does not exist
+    async fn head_object(&self, bucket: &str, key: &str) -> Result<Option<BackupMetadata>>;
     async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<BackupMetadata>>;
     async fn delete(&self, bucket: &str, key: &str) -> Result<()>;
+
+    /// Keys `delete_batch` deletes with a single request
+    fn delete_batch_size(&self) -> usize {
+        1
+    }
+
+    /// Delete several objects, returning the outcome for every key in order
+    ///
+    /// Backends without a batch API delete the keys one by one, up to `concurrency`
+    /// at a time.
+    async fn delete_batch(
+        &self,
+        bucket: &str,
+        keys: &[String],
+        concurrency: usize,
+    ) -> Vec<Result<()>> {
+        // Futures are lazy, so collecting them first still keeps `concurrency` in flight
+        let deletes: Vec<_> = keys.iter().map(|key| self.delete(bucket, key)).collect();
+        stream::iter(deletes)
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+}
+
+/// An object being uploaded in parts, started by `StorageBackend::start_upload`
+///
+/// Parts are uploaded in order. All of them but the last must have the same size,
+/// at least `MIN_PART_SIZE` and a multiple of `PART_SIZE_MULTIPLE`. The object
+/// exists once completed; an upload that is neither completed nor aborted may be
+/// kept by the storage until its lifecycle rules remove it.
+#[async_trait]
+pub trait PartUpload: Send {
+    async fn upload_part(&mut self, data: Bytes) -> Result<()>;
+    /// Create the object from the parts uploaded
+    async fn complete(self: Box<Self>) -> Result<()>;
+    /// Discard the parts uploaded
+    async fn abort(self: Box<Self>) -> Result<()>;
+}
+
+/// Parts collected in memory and uploaded as one object when complete
+struct BufferedUpload<'a, S: ?Sized> {
+    storage: &'a S,
+    bucket: String,
+    key: String,
+    metadata: HashMap<String, String>,
+    tags: HashMap<String, String>,
+    data: Vec<u8>,
+}
+
+#[async_trait]
+impl<S: StorageBackend + ?Sized> PartUpload for BufferedUpload<'_, S> {
+    async fn upload_part(&mut self, data: Bytes) -> Result<()> {
+        self.data.extend_from_slice(&data);
+        Ok(())
+    }
+
+    async fn complete(self: Box<Self>) -> Result<()> {
+        self.storage
+            .upload(
+                &self.bucket,
+                &self.key,
+                Bytes::from(self.data),
+                &self.metadata,
+                &self.tags,
+            )
+            .await
+    }
+
+    async fn abort(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether `error` is a storage operation failing on an object that does not exist
+pub fn is_not_found(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<BackupError>(),
+        Some(BackupError::NotFound(_))
+    )
+}
+
+/// Whether `error` is a storage operation failing or timing out, as opposed to e.g.
+/// an invalid dump or a missing object
+pub fn is_storage_failure(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<BackupError>(),
+        Some(BackupError::S3(_) | BackupError::Gcs(_) | BackupError::Timeout { .. })
+    )
 }
 
 #[derive(Debug, Clone)]
 pub struct BackupMetadata {
     pub key: String,
     pub timestamp: DateTime<Utc>,
-    #[allow(dead_code)]
     pub size: i64,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum StorageType {
     S3,
     GS,
 }
 
-/// Storage URL, "(s3|gs)://bucket/prefix"
-#[derive(Debug, Clone)]
-pub struct StorageConfig {
+impl StorageType {
+    /// Backend name used in metric labels
+    pub fn label(&self) -> &'static str {
+        match self {
+            StorageType::S3 => "s3",
+            StorageType::GS => "gcs",
+        }
+    }
+}
+
+/// Bucket and prefix of a destination, from its storage URL or typed `storage`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageLocation {
     pub storage_type: StorageType,
     pub bucket: String,
     pub prefix: String,
+    /// Region replacing the one of the client settings, S3 only
+    pub region: Option<String>,
+    /// Endpoint replacing the one of the client settings
+    pub endpoint: Option<String>,
 }
 
-#[derive(Debug)]
-pub struct ParseError(String);
-
-impl fmt::Display for ParseError {
+impl fmt::Display for StorageLocation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Parse error: {}", self.0)
+        let scheme = match self.storage_type {
+            StorageType::S3 => "s3",
+            StorageType::GS => "gs",
+        };
+        write!(
+            f,
+            "{}://{}/{}",
+            scheme,
+            self.bucket,
+            self.prefix.trim_start_matches('/')
+        )
     }
 }
 
-impl std::error::Error for ParseError {}
+/// Reasons a storage URL is rejected
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum ParseError {
+    #[error("Invalid format: missing 's3://' or 'gs://' scheme")]
+    MissingScheme,
+    #[error("Invalid storage type: {0}")]
+    UnknownScheme(String),
+    #[error("Invalid format: empty bucket name")]
+    EmptyBucket,
+    #[error("Invalid prefix '{0}': empty path segment")]
+    InvalidPrefix(String),
+    #[error("Invalid bucket name '{0}'")]
+    InvalidBucket(String),
+    #[error("Both storage_url and storage are set")]
+    Conflicting,
+    #[error("Neither storage_url nor storage is set")]
+    Missing,
+}
 
-pub async fn get_storage_client(storage_type: &StorageType) -> Result<Arc<dyn StorageBackend>> {
-    let client: Arc<dyn StorageBackend> = match storage_type {
-        StorageType::S3 => Arc::new(S3Storage::new().await?),
-        StorageType::GS => Arc::new(GcsStorage::new().await?),
+pub async fn get_storage_client(
+    storage: &StorageLocation,
+    s3: &S3Config,
+    gcs: &GcsConfig,
+    http: &StorageClientConfig,
+    timeout: Duration,
+    metrics: Arc<RwLock<Metrics>>,
+) -> Result<Arc<dyn StorageBackend>> {
+    // A typed `storage` may name the region and endpoint of its bucket
+    let client: Arc<dyn StorageBackend> = match storage.storage_type {
+        StorageType::S3 => {
+            let s3 = S3Config {
+                region: storage.region.clone().or_else(|| s3.region.clone()),
+                endpoint: storage.endpoint.clone().or_else(|| s3.endpoint.clone()),
+                ..s3.clone()
+            };
+            Arc::new(S3Storage::new(&s3, http, storage.to_string(), metrics.clone()).await?)
+        }
+        StorageType::GS => {
+            let gcs = GcsConfig {
+                endpoint: storage.endpoint.clone().or_else(|| gcs.endpoint.clone()),
+                ..gcs.clone()
+            };
+            Arc::new(GcsStorage::new(&gcs, http).await?)
+        }
     };
 
-    Ok(client)
+    Ok(Arc::new(InstrumentedStorage::new(
+        client,
+        storage.storage_type.label(),
+        timeout,
+        metrics,
+    )))
 }
 
-pub fn parse_storage_url(url: &str) -> Result<StorageConfig, ParseError> {
-    // Split on "://"
-    let parts: Vec<&str> = url.split("://").collect();
-    if parts.len() != 2 {
-        return Err(ParseError("Invalid format: missing '://'".to_string()));
+/// Content type of an uploaded object, by the suffix of its key
+pub fn content_type(key: &str) -> &'static str {
+    if key.ends_with(".json") {
+        "application/json"
+    } else if key.ends_with(".sha256") {
+        "text/plain"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// Read a PEM file of CA certificates, failing unless it holds at least one
+pub async fn read_ca_bundle(path: &Path) -> Result<Vec<u8>> {
+    let pem = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("Failed to read CA bundle {:?}", path))?;
+    let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+        .with_context(|| format!("Invalid CA bundle {:?}", path))?;
+    if certificates.is_empty() {
+        bail!("No certificates found in CA bundle {:?}", path);
+    }
+    Ok(pem)
+}
+
+/// reqwest client builder with the configured proxy and TLS settings
+pub async fn http_client_builder(config: &StorageClientConfig) -> Result<reqwest::ClientBuilder> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(url) = &config.proxy {
+        let proxy = reqwest::Proxy::all(url.as_str())
+            .map_err(|e| BackupError::Config(format!("Invalid storage.proxy: {}", e)))?;
+        let no_proxy = config
+            .no_proxy
+            .as_deref()
+            .and_then(reqwest::NoProxy::from_string);
+        builder = builder.proxy(proxy.no_proxy(no_proxy));
+    }
+    if let Some(path) = &config.ca_bundle {
+        let pem = read_ca_bundle(path).await?;
+        builder = builder.tls_certs_merge(reqwest::Certificate::from_pem_bundle(&pem)?);
     }
+    if config.insecure_skip_verify {
+        warn!(
+            "TLS certificate verification of storage requests is DISABLED by storage.insecure_skip_verify, anyone on the network path can read and alter backups"
+        );
+        builder = builder.tls_danger_accept_invalid_certs(true);
+    }
+    Ok(builder)
+}
+
+pub fn parse_storage_url(url: &str) -> Result<StorageLocation, ParseError> {
+    // Split on "://"
+    let (scheme, path) = match url.split_once("://") {
+        Some((scheme, path)) if !scheme.is_empty() => (scheme, path),
+        _ => return Err(ParseError::MissingScheme),
+    };
 
     // Parse storage type
-    let storage_type = match parts[0] {
+    let storage_type = match scheme {
         "s3" => StorageType::S3,
         "gs" => StorageType::GS,
-        _ => return Err(ParseError(format!("Invalid storage type: {}", parts[0]))),
+        _ => return Err(ParseError::UnknownScheme(scheme.to_string())),
     };
 
     // Split bucket and prefix
-    let path_parts: Vec<&str> = parts[1].splitn(2, '/').collect();
+    let path_parts: Vec<&str> = path.splitn(2, '/').collect();
 
     let bucket = path_parts[0].to_string();
     if bucket.is_empty() {
-        return Err(ParseError("Invalid format: empty bucket name".to_string()));
+        return Err(ParseError::EmptyBucket);
     }
 
     // If no prefix provided, default to "/"
@@ -87,10 +354,616 @@ pub fn parse_storage_url(url: &str) -> Result<StorageConfig, ParseError> {
     } else {
         path_parts[1].to_string()
     };
+    check_prefix(&prefix)?;
+
+    Ok(StorageLocation {
+        storage_type,
+        bucket,
+        prefix,
+        region: None,
+        endpoint: None,
+    })
+}
+
+/// Storage location of a destination given by `storage_url` or a typed `storage`
+///
+/// Exactly one of them must be set, an empty `url` counting as unset.
+pub fn storage_location(
+    url: &str,
+    target: Option<&StorageTarget>,
+) -> Result<StorageLocation, ParseError> {
+    let (storage_type, bucket, prefix, region, endpoint) = match (url, target) {
+        ("", None) => return Err(ParseError::Missing),
+        (url, None) => return parse_storage_url(url),
+        ("", Some(target)) => match target.clone() {
+            StorageTarget::S3 {
+                bucket,
+                prefix,
+                region,
+                endpoint,
+            } => (StorageType::S3, bucket, prefix, region, endpoint),
+            StorageTarget::Gcs {
+                bucket,
+                prefix,
+                endpoint,
+            } => (StorageType::GS, bucket, prefix, None, endpoint),
+        },
+        (_, Some(_)) => return Err(ParseError::Conflicting),
+    };
+
+    if bucket.is_empty() {
+        return Err(ParseError::EmptyBucket);
+    }
+    if bucket.contains('/') {
+        return Err(ParseError::InvalidBucket(bucket));
+    }
+    check_prefix(&prefix)?;
 
-    Ok(StorageConfig {
+    Ok(StorageLocation {
         storage_type,
         bucket,
         prefix,
+        region,
+        endpoint,
     })
 }
+
+/// Reject prefixes with an empty path segment
+fn check_prefix(prefix: &str) -> Result<(), ParseError> {
+    // Keys below a prefix like "a//b" or "a://b" are almost certainly a typo
+    if prefix.len() > 1 && (prefix.starts_with('/') || prefix.contains("//")) {
+        return Err(ParseError::InvalidPrefix(prefix.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_storage_url_bucket_and_prefix() {
+        let storage = parse_storage_url("s3://bucket/backups/redis").unwrap();
+        assert!(matches!(storage.storage_type, StorageType::S3));
+        assert_eq!(storage.bucket, "bucket");
+        assert_eq!(storage.prefix, "backups/redis");
+        assert_eq!(storage.to_string(), "s3://bucket/backups/redis");
+
+        let storage = parse_storage_url("gs://bucket").unwrap();
+        assert!(matches!(storage.storage_type, StorageType::GS));
+        assert_eq!(storage.prefix, "/");
+        assert_eq!(storage.to_string(), "gs://bucket/");
+
+        let storage = parse_storage_url("gs://b/").unwrap();
+        assert_eq!(storage.bucket, "b");
+        assert_eq!(storage.prefix, "");
+    }
+
+    #[test]
+    fn parse_storage_url_errors() {
+        for (url, error) in [
+            ("bucket/prefix", ParseError::MissingScheme),
+            ("://bucket", ParseError::MissingScheme),
+            (
+                "file:///backups",
+                ParseError::UnknownScheme("file".to_string()),
+            ),
+            ("S3://bucket", ParseError::UnknownScheme("S3".to_string())),
+            ("s3://", ParseError::EmptyBucket),
+            ("gs:///prefix", ParseError::EmptyBucket),
+            (
+                "s3://bucket//prefix",
+                ParseError::InvalidPrefix("/prefix".to_string()),
+            ),
+            (
+                "s3://bucket/a//b",
+                ParseError::InvalidPrefix("a//b".to_string()),
+            ),
+            (
+                "s3://bucket/s3://b",
+                ParseError::InvalidPrefix("s3://b".to_string()),
+            ),
+        ] {
+            assert_eq!(parse_storage_url(url).unwrap_err(), error, "{}", url);
+        }
+    }
+
+    fn s3_target(bucket: &str, prefix: &str) -> StorageTarget {
+        StorageTarget::S3 {
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            region: Some("eu-central-1".to_string()),
+            endpoint: Some("http://127.0.0.1:9000".to_string()),
+        }
+    }
+
+    #[test]
+    fn storage_location_of_url_and_target() {
+        let url = storage_location("s3://bucket/backups/redis/", None).unwrap();
+        let target = storage_location("", Some(&s3_target("bucket", "backups/redis/"))).unwrap();
+        assert_eq!(target.storage_type, url.storage_type);
+        assert_eq!(target.bucket, url.bucket);
+        assert_eq!(target.prefix, url.prefix);
+        assert_eq!(target.to_string(), url.to_string());
+        assert_eq!((url.region, url.endpoint), (None, None));
+        assert_eq!(target.region.as_deref(), Some("eu-central-1"));
+        assert_eq!(target.endpoint.as_deref(), Some("http://127.0.0.1:9000"));
+
+        let gcs = StorageTarget::Gcs {
+            bucket: "bucket".to_string(),
+            prefix: String::new(),
+            endpoint: None,
+        };
+        assert_eq!(
+            storage_location("", Some(&gcs)).unwrap().to_string(),
+            storage_location("gs://bucket/", None).unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn storage_location_errors() {
+        let target = s3_target("bucket", "");
+        for (url, target, error) in [
+            ("", None, ParseError::Missing),
+            ("s3://bucket", Some(&target), ParseError::Conflicting),
+            ("s3://", None, ParseError::EmptyBucket),
+            ("", Some(&s3_target("", "")), ParseError::EmptyBucket),
+            (
+                "",
+                Some(&s3_target("bucket/backups", "")),
+                ParseError::InvalidBucket("bucket/backups".to_string()),
+            ),
+            (
+                "",
+                Some(&s3_target("bucket", "a//b")),
+                ParseError::InvalidPrefix("a//b".to_string()),
+            ),
+        ] {
+            assert_eq!(storage_location(url, target).unwrap_err(), error, "{}", url);
+        }
+    }
+
+    /// Requests received by `fake_s3`
+    #[derive(Default)]
+    struct FakeS3 {
+        /// Method and path of every request, without the query
+        requests: Vec<String>,
+        /// Keys of every DeleteObjects request
+        deletes: Vec<Vec<String>>,
+        /// Tags of the objects uploaded, by path
+        tags: HashMap<String, Vec<(String, String)>>,
+    }
+
+    /// Decode the URL encoded tags of an `x-amz-tagging` header
+    fn decode_tagging(tagging: &str) -> Vec<(String, String)> {
+        let decode = |value: &str| {
+            let mut bytes = Vec::new();
+            let mut rest = value.as_bytes();
+            while let [first, tail @ ..] = rest {
+                match (first, tail) {
+                    (b'%', [high, low, tail @ ..]) => {
+                        let hex = std::str::from_utf8(&[*high, *low]).unwrap().to_string();
+                        bytes.push(u8::from_str_radix(&hex, 16).unwrap());
+                        rest = tail;
+                    }
+                    _ => {
+                        bytes.push(*first);
+                        rest = tail;
+                    }
+                }
+            }
+            String::from_utf8(bytes).unwrap()
+        };
+        tagging
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (decode(key), decode(value)))
+            .collect()
+    }
+
+    /// Answer to a GetObjectTagging request
+    fn tagging_result(tags: &[(String, String)]) -> String {
+        let tags: String = tags
+            .iter()
+            .map(|(key, value)| format!("<Tag><Key>{}</Key><Value>{}</Value></Tag>", key, value))
+            .collect();
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><Tagging><TagSet>{}</TagSet></Tagging>",
+            tags
+        )
+    }
+
+    /// Values of every `<name>` element of an XML document, in order
+    fn xml_values(xml: &str, name: &str) -> Vec<String> {
+        let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+        xml.split(&open)
+            .skip(1)
+            .filter_map(|rest| rest.split_once(&close))
+            .map(|(value, _)| value.to_string())
+            .collect()
+    }
+
+    /// Answer to a DeleteObjects request, failing keys containing "denied"
+    ///
+    /// The errors are listed in reverse, as S3 does not keep the order of the request.
+    fn delete_result(body: &str) -> String {
+        let errors: String = xml_values(body, "Key")
+            .iter()
+            .rev()
+            .filter(|key| key.contains("denied"))
+            .map(|key| {
+                format!(
+                    "<Error><Key>{}</Key><Code>AccessDenied</Code><Message>Access Denied</Message></Error>",
+                    key
+                )
+            })
+            .collect();
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><DeleteResult>{}</DeleteResult>",
+            errors
+        )
+    }
+
+    /// S3 endpoint answering every request with 200, recording what it received
+    async fn fake_s3() -> (String, Arc<std::sync::Mutex<FakeS3>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let fake = Arc::new(std::sync::Mutex::new(FakeS3::default()));
+        let recorded = fake.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 4096];
+                let head_end = loop {
+                    let n = socket.read(&mut buffer).await.unwrap();
+                    assert!(n > 0, "connection closed before the request ended");
+                    request.extend_from_slice(&buffer[..n]);
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                };
+                let head = String::from_utf8_lossy(&request[..head_end]).to_lowercase();
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .map_or(0, |length| length.trim().parse().unwrap());
+                while request.len() < head_end + length {
+                    let n = socket.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..n]);
+                }
+
+                let line = String::from_utf8_lossy(&request[..head_end]);
+                let mut fields = line.split_whitespace();
+                let method = fields.next().unwrap_or_default();
+                let target = fields.next().unwrap_or_default();
+                let (path, query) = target.split_once('?').unwrap_or((target, ""));
+                let body = String::from_utf8_lossy(&request[head_end..]);
+
+                let response = {
+                    let mut fake = recorded.lock().unwrap();
+                    fake.requests.push(format!("{} {}", method, path));
+                    let has = |name: &str| query.split('&').any(|field| field == name);
+                    if method == "POST" && has("delete") {
+                        fake.deletes.push(xml_values(&body, "Key"));
+                        delete_result(&body)
+                    } else if method == "GET" && has("tagging") {
+                        tagging_result(fake.tags.get(path).map_or(&[], Vec::as_slice))
+                    } else {
+                        // Header names are case insensitive, the tags are not
+                        let tagging = line.lines().find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.eq_ignore_ascii_case("x-amz-tagging").then_some(value)
+                        });
+                        if method == "PUT"
+                            && let Some(tagging) = tagging
+                        {
+                            fake.tags
+                                .insert(path.to_string(), decode_tagging(tagging.trim()));
+                        }
+                        String::new()
+                    }
+                };
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    response.len()
+                );
+                socket
+                    .write_all([head, response].concat().as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+        (endpoint, fake)
+    }
+
+    /// Tags of the objects uploaded by `upload_request`
+    fn upload_tags() -> HashMap<String, String> {
+        [
+            ("node", "node-1"),
+            ("format", "rdb"),
+            ("owner", "Platform Team/Redis"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+    }
+
+    /// Upload a tagged backup through a client for `location`, returning its key
+    async fn upload_request(location: &StorageLocation, s3: &S3Config) -> Result<String> {
+        let metrics = Metrics::new(&crate::config::get_default_config().metrics)?;
+        let client = get_storage_client(
+            location,
+            s3,
+            &GcsConfig::default(),
+            &StorageClientConfig::default(),
+            Duration::from_secs(10),
+            Arc::new(RwLock::new(metrics)),
+        )
+        .await?;
+
+        let key = format!("{}node-1.rdb", location.prefix);
+        client
+            .upload(
+                &location.bucket,
+                &key,
+                Bytes::from_static(b"REDIS0011"),
+                &HashMap::new(),
+                &upload_tags(),
+            )
+            .await?;
+        Ok(key)
+    }
+
+    /// Tags of an object as read back with GetObjectTagging, sorted by key
+    async fn read_tags(endpoint: &str, key: &str) -> Vec<(String, String)> {
+        use aws_sdk_s3::config::{BehaviorVersion, Credentials, Region};
+
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .endpoint_url(endpoint)
+            .force_path_style(true)
+            .credentials_provider(Credentials::new("access", "secret", None, None, "test"))
+            .build();
+        let tagging = aws_sdk_s3::Client::from_conf(config)
+            .get_object_tagging()
+            .bucket("bucket")
+            .key(key)
+            .send()
+            .await
+            .unwrap();
+        let mut tags: Vec<_> = tagging
+            .tag_set()
+            .iter()
+            .map(|tag| (tag.key().to_string(), tag.value().to_string()))
+            .collect();
+        tags.sort();
+        tags
+    }
+
+    #[tokio::test]
+    async fn both_storage_shapes_upload_to_s3() {
+        let (endpoint, fake) = fake_s3().await;
+        let s3 = S3Config {
+            access_key_id: Some("access".to_string()),
+            secret_access_key: Some("secret".to_string()),
+            force_path_style: true,
+            request_checksum: Some("none".to_string()),
+            ..S3Config::default()
+        };
+
+        // The URL relies on the s3 block for region and endpoint
+        let url = storage_location("s3://bucket/url/", None).unwrap();
+        let s3_with_endpoint = S3Config {
+            region: Some("us-east-1".to_string()),
+            endpoint: Some(endpoint.clone()),
+            ..s3.clone()
+        };
+        let url_key = upload_request(&url, &s3_with_endpoint).await.unwrap();
+
+        // The typed storage brings its own
+        let target = StorageTarget::S3 {
+            bucket: "bucket".to_string(),
+            prefix: "target/".to_string(),
+            region: Some("us-east-1".to_string()),
+            endpoint: Some(endpoint.clone()),
+        };
+        let target = storage_location("", Some(&target)).unwrap();
+        let target_key = upload_request(&target, &s3).await.unwrap();
+
+        assert_eq!(
+            fake.lock().unwrap().requests,
+            [
+                "PUT /bucket/url/node-1.rdb",
+                "PUT /bucket/target/node-1.rdb"
+            ]
+        );
+
+        let mut tags: Vec<_> = upload_tags().into_iter().collect();
+        tags.sort();
+        for key in [url_key, target_key] {
+            assert_eq!(read_tags(&endpoint, &key).await, tags, "{}", key);
+        }
+    }
+
+    /// S3 storage sending its requests to `endpoint`
+    async fn s3_storage(endpoint: String) -> S3Storage {
+        let s3 = S3Config {
+            region: Some("us-east-1".to_string()),
+            endpoint: Some(endpoint),
+            access_key_id: Some("access".to_string()),
+            secret_access_key: Some("secret".to_string()),
+            force_path_style: true,
+            request_checksum: Some("none".to_string()),
+            ..S3Config::default()
+        };
+        let metrics = Metrics::new(&crate::config::get_default_config().metrics).unwrap();
+        S3Storage::new(
+            &s3,
+            &StorageClientConfig::default(),
+            "s3://bucket/".to_string(),
+            Arc::new(RwLock::new(metrics)),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Errors of `delete_batch` results by index
+    fn failed_deletes(results: &[Result<()>]) -> Vec<(usize, String)> {
+        results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, result)| result.as_ref().err().map(|e| (i, e.to_string())))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn s3_deletes_up_to_1000_keys_per_request() {
+        let (endpoint, fake) = fake_s3().await;
+        let storage = s3_storage(endpoint).await;
+        let keys: Vec<String> = (0..2500)
+            .map(|i| match i % 700 {
+                0 => format!("backups/{:04}-denied.rdb", i),
+                _ => format!("backups/{:04}.rdb", i),
+            })
+            .collect();
+
+        let results = storage.delete_batch("bucket", &keys, 2).await;
+        assert_eq!(results.len(), keys.len());
+        let denied = "S3 error: AccessDenied: Access Denied".to_string();
+        assert_eq!(
+            failed_deletes(&results),
+            [
+                (0, denied.clone()),
+                (700, denied.clone()),
+                (1400, denied.clone()),
+                (2100, denied)
+            ]
+        );
+
+        // Requests run concurrently, so they may arrive in any order
+        let mut deletes = fake.lock().unwrap().deletes.clone();
+        deletes.sort();
+        let chunks: Vec<Vec<String>> = keys.chunks(1000).map(<[String]>::to_vec).collect();
+        assert_eq!(deletes, chunks);
+    }
+
+    /// Storage deleting keys one by one, slower for earlier keys, failing those
+    /// containing "denied"
+    #[derive(Default)]
+    struct SlowDeletes {
+        in_flight: std::sync::atomic::AtomicUsize,
+        max_in_flight: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl StorageBackend for SlowDeletes {
+        async fn upload(
+            &self,
+            _bucket: &str,
+            _key: &str,
+            _data: Bytes,
+            _metadata: &HashMap<String, String>,
+            _tags: &HashMap<String, String>,
+        ) -> Result<()> {
+            unreachable!()
+        }
+
+        async fn download(&self, _bucket: &str, _key: &str) -> Result<Bytes> {
+            unreachable!()
+        }
+
+        async fn download_range(
+            &self,
+            _bucket: &str,
+            _key: &str,
+            _start: u64,
+            _end: u64,
+        ) -> Result<Bytes> {
+            unreachable!()
+        }
+
+        async fn object_metadata(
+            &self,
+            _bucket: &str,
+            _key: &str,
+        ) -> Result<HashMap<String, String>> {
+            unreachable!()
+        }
+
+        async fn head_object(&self, _bucket: &str, _key: &str) -> Result<Option<BackupMetadata>> {
+            unreachable!()
+        }
+
+        async fn list(&self, _bucket: &str, _prefix: &str) -> Result<Vec<BackupMetadata>> {
+            unreachable!()
+        }
+
+        async fn delete(&self, _bucket: &str, key: &str) -> Result<()> {
+            use std::sync::atomic::Ordering;
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            let index: u64 = key.trim_end_matches("-denied").parse().unwrap();
+            tokio::time::sleep(Duration::from_millis(100 - index)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            match key.ends_with("denied") {
+                true => Err(anyhow::anyhow!("{} denied", index)),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn default_delete_batch_reports_every_key_in_order() {
+        let storage = SlowDeletes::default();
+        let keys: Vec<String> = (0..10)
+            .map(|i| match i {
+                2 | 7 => format!("{}-denied", i),
+                _ => i.to_string(),
+            })
+            .collect();
+
+        let results = storage.delete_batch("bucket", &keys, 3).await;
+        assert_eq!(results.len(), keys.len());
+        assert_eq!(
+            failed_deletes(&results),
+            [(2, "2 denied".to_string()), (7, "7 denied".to_string())]
+        );
+        let max_in_flight = storage
+            .max_in_flight
+            .load(std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(max_in_flight, 3);
+    }
+
+    #[tokio::test]
+    async fn both_storage_shapes_create_gcs_clients() {
+        let gcs = GcsConfig {
+            anonymous: true,
+            ..GcsConfig::default()
+        };
+        let target = StorageTarget::Gcs {
+            bucket: "bucket".to_string(),
+            prefix: "backups/".to_string(),
+            endpoint: Some("http://127.0.0.1:4443".to_string()),
+        };
+        for location in [
+            storage_location("gs://bucket/backups/", None).unwrap(),
+            storage_location("", Some(&target)).unwrap(),
+        ] {
+            let metrics = Metrics::new(&crate::config::get_default_config().metrics).unwrap();
+            get_storage_client(
+                &location,
+                &S3Config::default(),
+                &gcs,
+                &StorageClientConfig::default(),
+                Duration::from_secs(10),
+                Arc::new(RwLock::new(metrics)),
+            )
+            .await
+            .unwrap();
+        }
+    }
+}
@@ -1,41 +1,666 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use aws_sdk_s3::Client as S3Client;
+use aws_sdk_s3::config::SharedHttpClient;
+use aws_sdk_s3::config::http::{HttpRequest, HttpResponse};
+use aws_sdk_s3::config::{Credentials, Region, RequestChecksumCalculation};
+use aws_sdk_s3::error::{ConnectorError, ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::operation::create_multipart_upload::builders::CreateMultipartUploadFluentBuilder;
+use aws_sdk_s3::operation::put_object::builders::PutObjectFluentBuilder;
+use aws_sdk_s3::primitives::SdkBody;
+use aws_sdk_s3::types::{
+    ChecksumAlgorithm, CompletedMultipartUpload, CompletedPart, Delete, ObjectCannedAcl,
+    ObjectIdentifier, RequestPayer, ServerSideEncryption, StorageClass,
+};
+use aws_smithy_http_client::proxy::ProxyConfig;
+use aws_smithy_http_client::tls::rustls_provider::CryptoMode;
+use aws_smithy_http_client::tls::{self, TlsContext, TrustStore};
+use aws_smithy_http_client::{Builder as HttpClientBuilder, Connector};
+use aws_smithy_runtime_api::client::http::{
+    HttpConnector, HttpConnectorFuture, SharedHttpConnector, http_client_fn,
+};
+use base64::prelude::{BASE64_STANDARD, Engine};
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
+use futures_util::{StreamExt, stream};
+use md5::{Digest, Md5};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
 
-use super::{BackupMetadata, StorageBackend};
+use super::throttle::Throttle;
+use super::{
+    BackupMetadata, ObjectReader, PartUpload, StorageBackend, content_type, http_client_builder,
+    read_ca_bundle,
+};
 use crate::backup::BackupError;
+use crate::config::{S3Config, StorageClientConfig};
+use crate::metrics::{Metrics, write_metrics};
+
+/// Most keys a single DeleteObjects request accepts
+const MAX_DELETE_BATCH: usize = 1000;
 
 pub struct S3Storage {
     client: S3Client,
+    sse: Option<ServerSideEncryption>,
+    sse_kms_key_id: Option<String>,
+    storage_class: Option<StorageClass>,
+    acl: Option<ObjectCannedAcl>,
+    request_payer: Option<RequestPayer>,
+    checksum_algorithm: Option<ChecksumAlgorithm>,
+    content_md5: bool,
+    throttle: Option<Throttle>,
+    destination: String,
+    metrics: Arc<RwLock<Metrics>>,
 }
 
 impl S3Storage {
-    pub async fn new() -> Result<Self> {
-        let aws_config = aws_config::defaults(aws_config::BehaviorVersion::latest())
-            .load()
-            .await;
-        let s3_config = aws_sdk_s3::config::Builder::from(&aws_config);
+    pub async fn new(
+        config: &S3Config,
+        http: &StorageClientConfig,
+        destination: String,
+        metrics: Arc<RwLock<Metrics>>,
+    ) -> Result<Self> {
+        let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest());
+        // The default client already honors HTTPS_PROXY and NO_PROXY
+        if http.proxy.is_some() || http.ca_bundle.is_some() || http.insecure_skip_verify {
+            loader = loader.http_client(http_client(http).await?);
+        }
+        if let Some(region) = &config.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        if let Some(profile) = &config.profile {
+            loader = loader.profile_name(profile);
+        }
+        if let (Some(access_key_id), Some(secret_access_key)) =
+            (&config.access_key_id, &config.secret_access_key)
+        {
+            loader = loader.credentials_provider(Credentials::new(
+                access_key_id,
+                secret_access_key,
+                None,
+                None,
+                "redis-vault",
+            ));
+        }
+        let aws_config = loader.load().await;
+
+        let mut s3_config = aws_sdk_s3::config::Builder::from(&aws_config)
+            .force_path_style(config.force_path_style);
+        if let Some(endpoint) = &config.endpoint {
+            s3_config = s3_config.endpoint_url(endpoint);
+        }
+
+        // Some S3 compatible stores reject the checksums the SDK adds by default
+        let checksum = config.request_checksum.as_deref().map(str::to_lowercase);
+        let checksum_algorithm = match checksum.as_deref() {
+            None => None,
+            Some("none") => {
+                s3_config = s3_config
+                    .request_checksum_calculation(RequestChecksumCalculation::WhenRequired);
+                None
+            }
+            Some("crc32c") => Some(ChecksumAlgorithm::Crc32C),
+            Some("sha256") => Some(ChecksumAlgorithm::Sha256),
+            Some(_) => Some(ChecksumAlgorithm::Crc32),
+        };
         let client = S3Client::from_conf(s3_config.build());
 
-        Ok(S3Storage { client })
+        let sse = config
+            .sse
+            .as_deref()
+            .map(|sse| match sse.to_lowercase().as_str() {
+                "aws:kms" => ServerSideEncryption::AwsKms,
+                _ => ServerSideEncryption::Aes256,
+            });
+        let storage_class = config
+            .storage_class
+            .as_deref()
+            .map(|class| StorageClass::from(class.to_uppercase().as_str()));
+        let acl = config
+            .acl
+            .as_deref()
+            .map(|acl| ObjectCannedAcl::from(acl.to_lowercase().as_str()));
+        let request_payer = config.request_payer.then_some(RequestPayer::Requester);
+
+        let throttle = match config.throttle.enabled {
+            true => Some(Throttle::new(
+                &config.throttle,
+                destination.clone(),
+                metrics.clone(),
+            )?),
+            false => None,
+        };
+
+        Ok(S3Storage {
+            client,
+            sse,
+            sse_kms_key_id: config.sse_kms_key_id.clone(),
+            storage_class,
+            acl,
+            request_payer,
+            checksum_algorithm,
+            content_md5: config.content_md5,
+            throttle,
+            destination,
+            metrics,
+        })
     }
-}
 
-#[async_trait]
-impl StorageBackend for S3Storage {
-    async fn upload(&self, bucket: &str, key: &str, data: Bytes) -> Result<()> {
+    /// Send a request, within the throttling limits if enabled
+    async fn send<T, E>(
+        &self,
+        request: impl Future<Output = Result<T, SdkError<E, HttpResponse>>>,
+    ) -> Result<T>
+    where
+        E: ProvideErrorMetadata,
+    {
+        Ok(self
+            .throttled(request)
+            .await
+            .map_err(|e| BackupError::S3(e.to_string()))?)
+    }
+
+    /// Send a request like `send`, keeping the SDK error for inspection
+    async fn throttled<T, E>(
+        &self,
+        request: impl Future<Output = Result<T, SdkError<E, HttpResponse>>>,
+    ) -> Result<T, SdkError<E, HttpResponse>>
+    where
+        E: ProvideErrorMetadata,
+    {
+        match &self.throttle {
+            Some(throttle) => throttle.run(request, is_throttled).await,
+            None => request.await,
+        }
+    }
+
+    /// PutObject request of an object with the configured encryption, storage class,
+    /// ACL and checksums
+    fn put_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Bytes,
+        metadata: &HashMap<String, String>,
+        tags: &HashMap<String, String>,
+    ) -> PutObjectFluentBuilder {
+        let content_md5 = self
+            .content_md5
+            .then(|| BASE64_STANDARD.encode(Md5::digest(&data)));
         self.client
             .put_object()
             .bucket(bucket)
             .key(key)
             .body(data.into())
-            .send()
+            .set_server_side_encryption(self.sse.clone())
+            .set_ssekms_key_id(self.sse_kms_key_id.clone())
+            .set_storage_class(self.storage_class.clone())
+            .set_acl(self.acl.clone())
+            .set_request_payer(self.request_payer.clone())
+            .set_checksum_algorithm(self.checksum_algorithm.clone())
+            .set_content_md5(content_md5)
+            .content_type(content_type(key))
+            .set_tagging(tagging(tags))
+            .set_metadata((!metadata.is_empty()).then(|| metadata.clone()))
+    }
+
+    /// CreateMultipartUpload request with the same settings as `put_object`
+    ///
+    /// The Content-MD5 of the parts is sent with each part.
+    fn create_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        metadata: &HashMap<String, String>,
+        tags: &HashMap<String, String>,
+    ) -> CreateMultipartUploadFluentBuilder {
+        self.client
+            .create_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .set_server_side_encryption(self.sse.clone())
+            .set_ssekms_key_id(self.sse_kms_key_id.clone())
+            .set_storage_class(self.storage_class.clone())
+            .set_acl(self.acl.clone())
+            .set_request_payer(self.request_payer.clone())
+            .set_checksum_algorithm(self.checksum_algorithm.clone())
+            .content_type(content_type(key))
+            .set_tagging(tagging(tags))
+            .set_metadata((!metadata.is_empty()).then(|| metadata.clone()))
+    }
+
+    /// Delete up to `MAX_DELETE_BATCH` keys with one DeleteObjects request
+    async fn delete_objects(&self, bucket: &str, keys: &[String]) -> Vec<Result<()>> {
+        let delete = keys
+            .iter()
+            .map(|key| ObjectIdentifier::builder().key(key).build())
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|objects| {
+                Delete::builder()
+                    .set_objects(Some(objects))
+                    .quiet(true)
+                    .build()
+            });
+        let response = match delete {
+            Ok(delete) => {
+                self.send(
+                    self.client
+                        .delete_objects()
+                        .bucket(bucket)
+                        .delete(delete)
+                        .set_request_payer(self.request_payer.clone())
+                        .send(),
+                )
+                .await
+            }
+            Err(e) => Err(BackupError::S3(e.to_string()).into()),
+        };
+
+        // Quiet mode only reports the keys that could not be deleted
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                return keys
+                    .iter()
+                    .map(|_| Err(BackupError::S3(e.to_string()).into()))
+                    .collect();
+            }
+        };
+        let failed: HashMap<&str, String> = response
+            .errors()
+            .iter()
+            .filter_map(|error| {
+                let message = format!(
+                    "{}: {}",
+                    error.code().unwrap_or("Error"),
+                    error.message().unwrap_or_default()
+                );
+                Some((error.key()?, message))
+            })
+            .collect();
+
+        keys.iter()
+            .map(|key| match failed.get(key.as_str()) {
+                Some(message) => Err(BackupError::S3(message.clone()).into()),
+                None => Ok(()),
+            })
+            .collect()
+    }
+}
+
+/// A multipart upload of an object
+struct S3PartUpload<'a> {
+    storage: &'a S3Storage,
+    bucket: String,
+    key: String,
+    upload_id: String,
+    parts: Vec<CompletedPart>,
+}
+
+#[async_trait]
+impl PartUpload for S3PartUpload<'_> {
+    async fn upload_part(&mut self, data: Bytes) -> Result<()> {
+        let storage = self.storage;
+        let part_number = self.parts.len() as i32 + 1;
+        let content_md5 = storage
+            .content_md5
+            .then(|| BASE64_STANDARD.encode(Md5::digest(&data)));
+        let response = storage
+            .send(
+                storage
+                    .client
+                    .upload_part()
+                    .bucket(&self.bucket)
+                    .key(&self.key)
+                    .upload_id(&self.upload_id)
+                    .part_number(part_number)
+                    .body(data.into())
+                    .set_request_payer(storage.request_payer.clone())
+                    .set_checksum_algorithm(storage.checksum_algorithm.clone())
+                    .set_content_md5(content_md5)
+                    .send(),
+            )
+            .await?;
+
+        // The checksums of the parts are repeated when completing the upload
+        self.parts.push(
+            CompletedPart::builder()
+                .part_number(part_number)
+                .set_e_tag(response.e_tag)
+                .set_checksum_crc32(response.checksum_crc32)
+                .set_checksum_crc32_c(response.checksum_crc32_c)
+                .set_checksum_sha256(response.checksum_sha256)
+                .build(),
+        );
+        Ok(())
+    }
+
+    async fn complete(self: Box<Self>) -> Result<()> {
+        let storage = self.storage;
+        storage
+            .send(
+                storage
+                    .client
+                    .complete_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(&self.key)
+                    .upload_id(&self.upload_id)
+                    .multipart_upload(
+                        CompletedMultipartUpload::builder()
+                            .set_parts(Some(self.parts))
+                            .build(),
+                    )
+                    .set_request_payer(storage.request_payer.clone())
+                    .send(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn abort(self: Box<Self>) -> Result<()> {
+        let storage = self.storage;
+        storage
+            .send(
+                storage
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(&self.key)
+                    .upload_id(&self.upload_id)
+                    .set_request_payer(storage.request_payer.clone())
+                    .send(),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Whether S3 rejected a request to slow down the request rate
+fn is_throttled<E: ProvideErrorMetadata>(error: &SdkError<E, HttpResponse>) -> bool {
+    error.code() == Some("SlowDown")
+        || error
+            .raw_response()
+            .is_some_and(|response| response.status().as_u16() == 503)
+}
+
+/// HTTPS client of the SDK with the configured proxy and CA certificates
+///
+/// Mirrors the SDK's default client, which is built the same way with the proxy
+/// taken from the environment and only the system roots trusted.
+async fn http_client(config: &StorageClientConfig) -> Result<SharedHttpClient> {
+    if config.insecure_skip_verify {
+        return insecure_http_client(config).await;
+    }
+
+    let proxy = match &config.proxy {
+        Some(url) => {
+            let proxy = ProxyConfig::all(url.as_str())
+                .map_err(|e| BackupError::Config(format!("Invalid storage.proxy: {}", e)))?;
+            match &config.no_proxy {
+                Some(rules) => proxy.no_proxy(rules),
+                None => proxy,
+            }
+        }
+        None => ProxyConfig::from_env(),
+    };
+
+    let mut trust_store = TrustStore::empty().with_native_roots(true);
+    if let Some(path) = &config.ca_bundle {
+        trust_store = trust_store.with_pem_certificate(read_ca_bundle(path).await?);
+    }
+    let tls_context = TlsContext::builder()
+        .with_trust_store(trust_store)
+        .build()
+        .map_err(|e| BackupError::S3(e.to_string()))?;
+
+    Ok(
+        HttpClientBuilder::new().build_with_connector_fn(move |settings, components| {
+            let mut builder = Connector::builder()
+                .tls_provider(tls::Provider::Rustls(CryptoMode::AwsLc))
+                .tls_context(tls_context.clone());
+            builder.set_connector_settings(settings.cloned());
+            if let Some(components) = components {
+                builder.set_sleep_impl(components.sleep_impl());
+            }
+            builder.set_proxy_config(Some(proxy.clone()));
+            builder.build()
+        }),
+    )
+}
+
+/// HTTPS client of the SDK accepting any certificate
+///
+/// The SDK's own client always verifies certificates, so requests go through
+/// reqwest instead, as for GCS.
+async fn insecure_http_client(config: &StorageClientConfig) -> Result<SharedHttpClient> {
+    let client = http_client_builder(config)
+        .await?
+        // The SDK follows no redirects, S3 answers with errors naming the right region
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .map_err(|e| BackupError::S3(e.to_string()))?;
+    let connector = SharedHttpConnector::new(ReqwestConnector { client });
+    Ok(http_client_fn(move |_, _| connector.clone()))
+}
+
+/// Connector sending the requests of the SDK with reqwest
+#[derive(Debug)]
+struct ReqwestConnector {
+    client: reqwest::Client,
+}
+
+impl HttpConnector for ReqwestConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let client = self.client.clone();
+        HttpConnectorFuture::new(async move {
+            let request = request
+                .try_into_http1x()
+                .map_err(|e| ConnectorError::other(e.into(), None))?
+                .map(reqwest::Body::wrap);
+            let request = reqwest::Request::try_from(request)
+                .map_err(|e| ConnectorError::other(e.into(), None))?;
+            let response = client.execute(request).await.map_err(|e| {
+                // Lets the SDK retry transient failures
+                if e.is_timeout() {
+                    ConnectorError::timeout(e.into())
+                } else if e.is_connect() || e.is_request() {
+                    ConnectorError::io(e.into())
+                } else {
+                    ConnectorError::other(e.into(), None)
+                }
+            })?;
+            let response = http::Response::from(response).map(SdkBody::from_body_1_x);
+            HttpResponse::try_from(response).map_err(|e| ConnectorError::other(e.into(), None))
+        })
+    }
+}
+
+/// Tags as the URL encoded query string S3 expects, `None` without tags
+fn tagging(tags: &HashMap<String, String>) -> Option<String> {
+    let encode = |value: &str| -> String {
+        value
+            .bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                    (b as char).to_string()
+                }
+                _ => format!("%{:02X}", b),
+            })
+            .collect()
+    };
+
+    let mut pairs: Vec<_> = tags
+        .iter()
+        .map(|(key, value)| format!("{}={}", encode(key), encode(value)))
+        .collect();
+    pairs.sort();
+    (!pairs.is_empty()).then(|| pairs.join("&"))
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    async fn upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Bytes,
+        metadata: &HashMap<String, String>,
+        tags: &HashMap<String, String>,
+    ) -> Result<()> {
+        self.send(self.put_object(bucket, key, data, metadata, tags).send())
+            .await?;
+
+        Ok(())
+    }
+
+    async fn start_upload<'a>(
+        &'a self,
+        bucket: &str,
+        key: &str,
+        metadata: &HashMap<String, String>,
+        tags: &HashMap<String, String>,
+    ) -> Result<Box<dyn PartUpload + 'a>> {
+        let response = self
+            .send(
+                self.create_multipart_upload(bucket, key, metadata, tags)
+                    .send(),
+            )
+            .await?;
+        let upload_id = response
+            .upload_id
+            .ok_or_else(|| BackupError::S3(format!("No upload id for {}", key)))?;
+
+        Ok(Box::new(S3PartUpload {
+            storage: self,
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            upload_id,
+            parts: Vec::new(),
+        }))
+    }
+
+    async fn object_metadata(&self, bucket: &str, key: &str) -> Result<HashMap<String, String>> {
+        let response = self
+            .send(
+                self.client
+                    .head_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .set_request_payer(self.request_payer.clone())
+                    .send(),
+            )
+            .await?;
+
+        Ok(response.metadata.unwrap_or_default())
+    }
+
+    async fn head_object(&self, bucket: &str, key: &str) -> Result<Option<BackupMetadata>> {
+        let result = self
+            .throttled(
+                self.client
+                    .head_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .set_request_payer(self.request_payer.clone())
+                    .send(),
+            )
+            .await;
+        let response = match result {
+            Ok(response) => response,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => return Ok(None),
+            Err(e) => return Err(BackupError::S3(e.to_string()).into()),
+        };
+
+        Ok(Some(BackupMetadata {
+            key: key.to_string(),
+            timestamp: response
+                .last_modified
+                .and_then(|modified| DateTime::from_timestamp(modified.secs(), 0))
+                .unwrap_or_else(Utc::now),
+            size: response.content_length.unwrap_or(0),
+        }))
+    }
+
+    async fn download(&self, bucket: &str, key: &str) -> Result<Bytes> {
+        let result = self
+            .throttled(
+                self.client
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .set_request_payer(self.request_payer.clone())
+                    .send(),
+            )
+            .await;
+        let response = match result {
+            Ok(response) => response,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => {
+                return Err(BackupError::NotFound(key.to_string()).into());
+            }
+            Err(e) => return Err(BackupError::S3(e.to_string()).into()),
+        };
+
+        let data = response
+            .body
+            .collect()
             .await
             .map_err(|e| BackupError::S3(e.to_string()))?;
 
-        Ok(())
+        Ok(data.into_bytes())
+    }
+
+    async fn download_stream(&self, bucket: &str, key: &str) -> Result<ObjectReader> {
+        let result = self
+            .throttled(
+                self.client
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .set_request_payer(self.request_payer.clone())
+                    .send(),
+            )
+            .await;
+        match result {
+            Ok(response) => Ok(Box::new(response.body.into_async_read())),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => {
+                Err(BackupError::NotFound(key.to_string()).into())
+            }
+            Err(e) => Err(BackupError::S3(e.to_string()).into()),
+        }
+    }
+
+    async fn download_range(&self, bucket: &str, key: &str, start: u64, end: u64) -> Result<Bytes> {
+        if start > end {
+            return Ok(Bytes::new());
+        }
+
+        let result = self
+            .throttled(
+                self.client
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .range(format!("bytes={}-{}", start, end))
+                    .set_request_payer(self.request_payer.clone())
+                    .send(),
+            )
+            .await;
+        let response = match result {
+            Ok(response) => response,
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => {
+                return Err(BackupError::NotFound(key.to_string()).into());
+            }
+            Err(e) => return Err(BackupError::S3(e.to_string()).into()),
+        };
+
+        let data = response
+            .body
+            .collect()
+            .await
+            .map_err(|e| BackupError::S3(e.to_string()))?;
+
+        Ok(data.into_bytes())
     }
 
     async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<BackupMetadata>> {
@@ -43,16 +668,23 @@ impl StorageBackend for S3Storage {
         let mut continuation_token = None;
 
         loop {
-            let mut request = self.client.list_objects_v2().bucket(bucket).prefix(prefix);
+            let mut request = self
+                .client
+                .list_objects_v2()
+                .bucket(bucket)
+                .prefix(prefix)
+                .set_request_payer(self.request_payer.clone());
 
             if let Some(token) = continuation_token {
                 request = request.continuation_token(token);
             }
 
-            let response = request
-                .send()
+            let response = self.send(request.send()).await?;
+            write_metrics(&self.metrics)
                 .await
-                .map_err(|e| BackupError::S3(e.to_string()))?;
+                .list_pages_total
+                .with_label_values(&[self.destination.as_str()])
+                .inc();
 
             if let Some(contents) = response.contents {
                 for object in contents {
@@ -78,14 +710,134 @@ impl StorageBackend for S3Storage {
     }
 
     async fn delete(&self, bucket: &str, key: &str) -> Result<()> {
-        self.client
-            .delete_object()
-            .bucket(bucket)
-            .key(key)
-            .send()
-            .await
-            .map_err(|e| BackupError::S3(e.to_string()))?;
+        self.send(
+            self.client
+                .delete_object()
+                .bucket(bucket)
+                .key(key)
+                .set_request_payer(self.request_payer.clone())
+                .send(),
+        )
+        .await?;
 
         Ok(())
     }
+
+    fn delete_batch_size(&self) -> usize {
+        MAX_DELETE_BATCH
+    }
+
+    async fn delete_batch(
+        &self,
+        bucket: &str,
+        keys: &[String],
+        concurrency: usize,
+    ) -> Vec<Result<()>> {
+        let batches: Vec<_> = keys
+            .chunks(MAX_DELETE_BATCH)
+            .map(|chunk| self.delete_objects(bucket, chunk))
+            .collect();
+        stream::iter(batches)
+            .buffered(concurrency.max(1))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::get_default_config;
+
+    async fn storage(config: S3Config) -> S3Storage {
+        let config = S3Config {
+            region: Some("eu-central-1".to_string()),
+            access_key_id: Some("access".to_string()),
+            secret_access_key: Some("secret".to_string()),
+            ..config
+        };
+        let metrics = Arc::new(RwLock::new(
+            Metrics::new(&get_default_config().metrics).unwrap(),
+        ));
+        S3Storage::new(
+            &config,
+            &StorageClientConfig::default(),
+            "s3://bucket/".to_string(),
+            metrics,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn uploads_carry_encryption_and_storage_class() {
+        let storage = storage(S3Config {
+            sse: Some("aws:kms".to_string()),
+            sse_kms_key_id: Some("alias/backups".to_string()),
+            storage_class: Some("standard_ia".to_string()),
+            acl: Some("bucket-owner-full-control".to_string()),
+            request_payer: true,
+            content_md5: true,
+            ..S3Config::default()
+        })
+        .await;
+        let metadata = HashMap::from([("node".to_string(), "node-1".to_string())]);
+        let tags = HashMap::from([("env".to_string(), "production".to_string())]);
+
+        let put = storage.put_object(
+            "bucket",
+            "backups/node-1.rdb",
+            Bytes::from_static(b"REDIS0011"),
+            &metadata,
+            &tags,
+        );
+        let put = put.as_input();
+        assert_eq!(
+            put.get_server_side_encryption(),
+            &Some(ServerSideEncryption::AwsKms)
+        );
+        assert_eq!(put.get_ssekms_key_id().as_deref(), Some("alias/backups"));
+        assert_eq!(put.get_storage_class(), &Some(StorageClass::StandardIa));
+        assert_eq!(
+            put.get_acl(),
+            &Some(ObjectCannedAcl::BucketOwnerFullControl)
+        );
+        assert_eq!(put.get_request_payer(), &Some(RequestPayer::Requester));
+        assert_eq!(
+            put.get_content_md5().as_deref(),
+            Some(BASE64_STANDARD.encode(Md5::digest(b"REDIS0011")).as_str())
+        );
+        assert_eq!(put.get_tagging().as_deref(), Some("env=production"));
+        assert_eq!(put.get_metadata(), &Some(metadata.clone()));
+
+        // Streamed uploads are created with the same settings
+        let create =
+            storage.create_multipart_upload("bucket", "backups/node-1.rdb", &metadata, &tags);
+        let create = create.as_input();
+        assert_eq!(
+            create.get_server_side_encryption(),
+            &Some(ServerSideEncryption::AwsKms)
+        );
+        assert_eq!(create.get_ssekms_key_id().as_deref(), Some("alias/backups"));
+        assert_eq!(create.get_storage_class(), &Some(StorageClass::StandardIa));
+        assert_eq!(create.get_tagging().as_deref(), Some("env=production"));
+        assert_eq!(create.get_metadata(), &Some(metadata.clone()));
+    }
+
+    #[tokio::test]
+    async fn uploads_without_settings_leave_them_to_the_bucket() {
+        let storage = storage(S3Config::default()).await;
+        let empty = HashMap::new();
+        let put = storage.put_object("bucket", "backups/node-1.rdb", Bytes::new(), &empty, &empty);
+        let put = put.as_input();
+        assert_eq!(put.get_server_side_encryption(), &None);
+        assert_eq!(put.get_storage_class(), &None);
+        assert_eq!(put.get_acl(), &None);
+        assert_eq!(put.get_content_md5(), &None);
+        assert_eq!(put.get_tagging(), &None);
+        assert_eq!(put.get_metadata(), &None);
+    }
 }
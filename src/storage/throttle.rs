@@ -0,0 +1,372 @@
+//! Adaptive request throttling
+//!
+//! Object stores answer request bursts with throttling errors, e.g. S3 `SlowDown`.
+//! Retrying at the same rate only adds load, so a throttled response halves the
+//! number of concurrent requests and doubles the delay between request starts.
+//! Successful requests recover both step by step: first the delay shrinks, then
+//! concurrency grows by one for every round of successful requests.
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{RwLock, Semaphore};
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::config::S3ThrottleConfig;
+use crate::metrics::{Metrics, write_metrics};
+
+/// Delay between request starts after the first throttled response
+const MIN_DELAY: Duration = Duration::from_millis(100);
+
+/// Current limits, adjusted after every request
+struct State {
+    /// Concurrent requests allowed
+    concurrency: usize,
+    /// Permits of the semaphore, above `concurrency` until requests finish
+    permits: usize,
+    /// Minimum time between request starts
+    delay: Duration,
+    /// Earliest start of the next request
+    next_start: Instant,
+    /// Successful requests since the limits last changed
+    successes: usize,
+    /// Number of slow downs, so a burst of throttled requests only counts once
+    slow_downs: u64,
+}
+
+/// AIMD limiter of the requests to one destination
+pub struct Throttle {
+    semaphore: Semaphore,
+    state: Mutex<State>,
+    max_concurrency: usize,
+    max_delay: Duration,
+    destination: String,
+    metrics: Arc<RwLock<Metrics>>,
+}
+
+impl Throttle {
+    pub fn new(
+        config: &S3ThrottleConfig,
+        destination: String,
+        metrics: Arc<RwLock<Metrics>>,
+    ) -> anyhow::Result<Self> {
+        let max_concurrency = config.max_concurrency.max(1);
+        let max_delay = humantime::parse_duration(&config.max_delay)?;
+
+        Ok(Throttle {
+            semaphore: Semaphore::new(max_concurrency),
+            state: Mutex::new(State {
+                concurrency: max_concurrency,
+                permits: max_concurrency,
+                delay: Duration::ZERO,
+                next_start: Instant::now(),
+                successes: 0,
+                slow_downs: 0,
+            }),
+            max_concurrency,
+            max_delay,
+            destination,
+            metrics,
+        })
+    }
+
+    /// Run `request` within the current limits, adjusting them to its outcome
+    pub async fn run<T, E>(
+        &self,
+        request: impl Future<Output = Result<T, E>>,
+        is_throttled: impl Fn(&E) -> bool,
+    ) -> Result<T, E> {
+        // The semaphore is never closed
+        let permit = self.semaphore.acquire().await.ok();
+
+        let (start, slow_downs) = {
+            let mut state = self.lock();
+            let start = state.next_start.max(Instant::now());
+            state.next_start = start + state.delay;
+            (start, state.slow_downs)
+        };
+        tokio::time::sleep_until(start).await;
+
+        let result = request.await;
+        let throttled = result.as_ref().err().is_some_and(is_throttled);
+
+        let (concurrency, delay) = {
+            let mut state = self.lock();
+            // Requests started before the last slow down ran at the old limits
+            if throttled && state.slow_downs == slow_downs {
+                self.slow_down(&mut state);
+            } else if result.is_ok() {
+                self.speed_up(&mut state);
+            }
+
+            // Drop permits exceeding a reduced concurrency instead of returning them
+            if state.permits > state.concurrency
+                && let Some(permit) = permit
+            {
+                permit.forget();
+                state.permits -= 1;
+            }
+            (state.concurrency, state.delay)
+        };
+
+        let metrics = write_metrics(&self.metrics).await;
+        let labels = [self.destination.as_str()];
+        if throttled {
+            metrics
+                .storage_throttled_requests_total
+                .with_label_values(&labels)
+                .inc();
+        }
+        metrics
+            .storage_throttle_concurrency
+            .with_label_values(&labels)
+            .set(concurrency as i64);
+        metrics
+            .storage_throttle_delay_seconds
+            .with_label_values(&labels)
+            .set(delay.as_secs_f64());
+
+        result
+    }
+
+    /// Halve the concurrency and double the delay
+    fn slow_down(&self, state: &mut State) {
+        state.slow_downs += 1;
+        state.successes = 0;
+        state.concurrency = (state.concurrency / 2).max(1);
+        state.delay = (state.delay * 2).clamp(MIN_DELAY, self.max_delay);
+
+        // Idle permits can be dropped right away, busy ones once their request finishes
+        while state.permits > state.concurrency {
+            match self.semaphore.try_acquire() {
+                Ok(permit) => {
+                    permit.forget();
+                    state.permits -= 1;
+                }
+                Err(_) => break,
+            }
+        }
+
+        warn!(
+            "Storage {} is throttling requests, reducing to {} concurrent request(s) {:?} apart",
+            self.destination, state.concurrency, state.delay
+        );
+    }
+
+    /// Recover one step after a round of successful requests
+    fn speed_up(&self, state: &mut State) {
+        if state.delay.is_zero() && state.concurrency == self.max_concurrency {
+            return;
+        }
+        state.successes += 1;
+        if state.successes < state.concurrency {
+            return;
+        }
+        state.successes = 0;
+
+        if !state.delay.is_zero() {
+            state.delay /= 2;
+            if state.delay < MIN_DELAY {
+                state.delay = Duration::ZERO;
+            }
+        } else {
+            state.concurrency += 1;
+            if state.permits < state.concurrency {
+                self.semaphore.add_permits(1);
+                state.permits += 1;
+            }
+        }
+
+        if state.delay.is_zero() && state.concurrency == self.max_concurrency {
+            info!(
+                "Storage {} is no longer throttling requests",
+                self.destination
+            );
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        // The state stays consistent even if a holder panicked
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::get_default_config;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const DESTINATION: &str = "s3://bucket/";
+
+    fn throttle(max_concurrency: usize, max_delay: &str) -> Throttle {
+        let config = S3ThrottleConfig {
+            enabled: true,
+            max_concurrency,
+            max_delay: max_delay.to_string(),
+        };
+        let metrics = Metrics::new(&get_default_config().metrics).unwrap();
+        Throttle::new(
+            &config,
+            DESTINATION.to_string(),
+            Arc::new(RwLock::new(metrics)),
+        )
+        .unwrap()
+    }
+
+    /// Run a request taking 10ms with `result`, returning the limits afterwards
+    async fn request(throttle: &Throttle, result: Result<(), &'static str>) -> (usize, Duration) {
+        let _ = throttle
+            .run(
+                async {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    result
+                },
+                |e| *e == "SlowDown",
+            )
+            .await;
+        limits(throttle)
+    }
+
+    fn limits(throttle: &Throttle) -> (usize, Duration) {
+        let state = throttle.lock();
+        (state.concurrency, state.delay)
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttled_responses_halve_concurrency_and_double_the_delay() {
+        let throttle = throttle(8, "1s");
+        assert_eq!(request(&throttle, Ok(())).await, (8, Duration::ZERO));
+        // Other errors are not a reason to slow down
+        assert_eq!(
+            request(&throttle, Err("AccessDenied")).await,
+            (8, Duration::ZERO)
+        );
+
+        let mut limits = Vec::new();
+        for _ in 0..5 {
+            limits.push(request(&throttle, Err("SlowDown")).await);
+        }
+        assert_eq!(
+            limits,
+            [
+                (4, ms(100)),
+                (2, ms(200)),
+                (1, ms(400)),
+                (1, ms(800)),
+                (1, ms(1000))
+            ]
+        );
+        assert_eq!(throttle.semaphore.available_permits(), 1);
+
+        let metrics = throttle.metrics.read().await;
+        let labels = [DESTINATION];
+        assert_eq!(
+            metrics
+                .storage_throttled_requests_total
+                .with_label_values(&labels)
+                .get(),
+            5
+        );
+        assert_eq!(
+            metrics
+                .storage_throttle_concurrency
+                .with_label_values(&labels)
+                .get(),
+            1
+        );
+        assert_eq!(
+            metrics
+                .storage_throttle_delay_seconds
+                .with_label_values(&labels)
+                .get(),
+            1.0
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_burst_of_throttled_requests_slows_down_once() {
+        let throttle = throttle(8, "10s");
+        let burst = (0..8).map(|_| request(&throttle, Err("SlowDown")));
+        futures_util::future::join_all(burst).await;
+
+        // All eight ran at the old limits, so only the first response counts
+        assert_eq!(limits(&throttle), (4, ms(100)));
+        // The permits of the busy requests were dropped as they finished
+        assert_eq!(throttle.semaphore.available_permits(), 4);
+        assert_eq!(throttle.lock().permits, 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_start_the_delay_apart() {
+        let throttle = throttle(8, "10s");
+        request(&throttle, Err("SlowDown")).await;
+        request(&throttle, Err("SlowDown")).await;
+        assert_eq!(limits(&throttle), (2, ms(200)));
+
+        let running = AtomicUsize::new(0);
+        let most_running = AtomicUsize::new(0);
+        let starts = Mutex::new(Vec::new());
+        let requests = (0..6).map(|_| {
+            throttle.run(
+                async {
+                    starts.lock().unwrap().push(Instant::now());
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_running.fetch_max(now, Ordering::SeqCst);
+                    // Longer than the delay, so the concurrency is what limits
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok::<_, &str>(())
+                },
+                |e| *e == "SlowDown",
+            )
+        });
+        futures_util::future::join_all(requests).await;
+
+        let starts = starts.into_inner().unwrap();
+        assert_eq!(starts.len(), 6);
+        for pair in starts.windows(2) {
+            assert!(pair[1] - pair[0] >= ms(100), "{:?}", pair[1] - pair[0]);
+        }
+        assert!(starts[1] - starts[0] >= ms(200));
+        assert_eq!(most_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn successes_recover_the_delay_then_the_concurrency() {
+        let throttle = throttle(4, "10s");
+        for _ in 0..3 {
+            request(&throttle, Err("SlowDown")).await;
+        }
+        assert_eq!(limits(&throttle), (1, ms(400)));
+
+        let mut limits = Vec::new();
+        for _ in 0..10 {
+            limits.push(request(&throttle, Ok(())).await);
+        }
+        // One step per round of as many successes as requests may run at once
+        assert_eq!(
+            limits,
+            [
+                (1, ms(200)),
+                (1, ms(100)),
+                (1, Duration::ZERO),
+                (2, Duration::ZERO),
+                (2, Duration::ZERO),
+                (3, Duration::ZERO),
+                (3, Duration::ZERO),
+                (3, Duration::ZERO),
+                (4, Duration::ZERO),
+                (4, Duration::ZERO),
+            ]
+        );
+        assert_eq!(throttle.semaphore.available_permits(), 4);
+    }
+}